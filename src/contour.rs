@@ -0,0 +1,535 @@
+//! Planar contour helpers working on the XY projection of closed toolpath loops.
+
+use cavalier_contours::polyline::{PlineCreation, PlineSource, PlineSourceMut, Polyline};
+use csgrs::float_types::Real;
+use nalgebra::Point3;
+
+use crate::ToolpathSegment;
+
+/// Loop vertices without the repeated closing point, if the path stores one.
+/// Open paths are returned as-is.
+pub(crate) fn loop_vertices(seg: &ToolpathSegment) -> &[Point3<Real>] {
+    match (seg.points.first(), seg.points.last()) {
+        (Some(first), Some(last)) if seg.closed && seg.points.len() > 1 && first == last => {
+            &seg.points[..seg.points.len() - 1]
+        }
+        _ => &seg.points,
+    }
+}
+
+/// Area centroid of a closed loop in XY, falling back to the vertex average for
+/// degenerate (zero-area) loops.
+pub(crate) fn centroid_xy(seg: &ToolpathSegment) -> Option<(Real, Real)> {
+    let verts = loop_vertices(seg);
+    if verts.is_empty() {
+        return None;
+    }
+    let area = seg.area_xy_closed();
+    if area.abs() > Real::EPSILON {
+        let (mut cx, mut cy) = (0.0, 0.0);
+        for (a, b) in seg.edges() {
+            let cross = a.x * b.y - b.x * a.y;
+            cx += (a.x + b.x) * cross;
+            cy += (a.y + b.y) * cross;
+        }
+        return Some((cx / (6.0 * area), cy / (6.0 * area)));
+    }
+    let n = verts.len() as Real;
+    Some((verts.iter().map(|p| p.x).sum::<Real>() / n, verts.iter().map(|p| p.y).sum::<Real>() / n))
+}
+
+/// Ramer-Douglas-Peucker simplification of an open polyline; endpoints are kept.
+pub(crate) fn douglas_peucker(points: &[Point3<Real>], epsilon: Real) -> Vec<Point3<Real>> {
+    if points.len() < 3 {
+        return points.to_vec();
+    }
+    let mut keep = vec![false; points.len()];
+    keep[0] = true;
+    keep[points.len() - 1] = true;
+
+    // Iterative split over (start, end) spans to avoid deep recursion on long contours.
+    let mut spans = vec![(0, points.len() - 1)];
+    while let Some((start, end)) = spans.pop() {
+        let (a, b) = (points[start], points[end]);
+        let mut worst = (0.0, start);
+        for (i, p) in points.iter().enumerate().take(end).skip(start + 1) {
+            let d = distance_to_segment(p, &a, &b);
+            if d > worst.0 {
+                worst = (d, i);
+            }
+        }
+        if worst.0 > epsilon {
+            keep[worst.1] = true;
+            spans.push((start, worst.1));
+            spans.push((worst.1, end));
+        }
+    }
+    points.iter().zip(keep).filter(|(_, k)| *k).map(|(p, _)| *p).collect()
+}
+
+/// 3D distance from `p` to the segment `a`-`b`.
+pub(crate) fn distance_to_segment(p: &Point3<Real>, a: &Point3<Real>, b: &Point3<Real>) -> Real {
+    let ab = b - a;
+    let len_sq = ab.norm_squared();
+    if len_sq <= 0.0 {
+        return (p - a).norm();
+    }
+    let t = ((p - a).dot(&ab) / len_sq).clamp(0.0, 1.0);
+    (p - (a + ab * t)).norm()
+}
+
+/// Even-odd ray cast of `p` against the XY projection of a closed loop.
+pub(crate) fn point_in_loop(p: &Point3<Real>, seg: &ToolpathSegment) -> bool {
+    let mut inside = false;
+    for (a, b) in seg.edges() {
+        if (a.y > p.y) != (b.y > p.y) {
+            let x_cross = a.x + (p.y - a.y) / (b.y - a.y) * (b.x - a.x);
+            if p.x < x_cross {
+                inside = !inside;
+            }
+        }
+    }
+    inside
+}
+
+/// XY distance from `p` to the segment `a`-`b`, and the parameter of the closest point.
+pub(crate) fn distance_to_edge_xy(p: &Point3<Real>, a: &Point3<Real>, b: &Point3<Real>) -> (Real, Real) {
+    let (dx, dy) = (b.x - a.x, b.y - a.y);
+    let len_sq = dx * dx + dy * dy;
+    let t = if len_sq > 0.0 {
+        (((p.x - a.x) * dx + (p.y - a.y) * dy) / len_sq).clamp(0.0, 1.0)
+    } else {
+        0.0
+    };
+    let (cx, cy) = (a.x + t * dx, a.y + t * dy);
+    (((p.x - cx).powi(2) + (p.y - cy).powi(2)).sqrt(), t)
+}
+
+/// True when `p` lies within `eps` of the loop's boundary in XY.
+pub(crate) fn on_loop_boundary(p: &Point3<Real>, seg: &ToolpathSegment, eps: Real) -> bool {
+    seg.edges().any(|(a, b)| distance_to_edge_xy(p, &a, &b).0 <= eps)
+}
+
+/// Whether `inner` sits inside `outer`: no vertex strictly outside, at least one
+/// strictly inside, and a smaller enclosed area. Loops that merely touch along a
+/// shared edge are not nested.
+pub(crate) fn loop_contains(outer: &ToolpathSegment, inner: &ToolpathSegment, eps: Real) -> bool {
+    if inner.area_xy_closed().abs() >= outer.area_xy_closed().abs() {
+        return false;
+    }
+    let mut strictly_inside = false;
+    for p in loop_vertices(inner) {
+        if on_loop_boundary(p, outer, eps) {
+            continue;
+        }
+        if !point_in_loop(p, outer) {
+            return false;
+        }
+        strictly_inside = true;
+    }
+    strictly_inside
+}
+
+/// How many other contours enclose each contour. Even depths are boundaries of
+/// material, odd depths are holes. Only closed loops can enclose anything; open
+/// paths get a depth too, but never raise anyone else's.
+pub(crate) fn nesting_depths(segments: &[ToolpathSegment], eps: Real) -> Vec<usize> {
+    segments
+        .iter()
+        .enumerate()
+        .map(|(i, inner)| {
+            segments
+                .iter()
+                .enumerate()
+                .filter(|&(j, outer)| {
+                    i != j && outer.closed && loop_vertices(outer).len() >= 3 && loop_contains(outer, inner, eps)
+                })
+                .count()
+        })
+        .collect()
+}
+
+/// Area enclosed by the XY convex hull of `points` (Andrew's monotone chain).
+pub(crate) fn convex_hull_area_xy<'a>(points: impl IntoIterator<Item = &'a Point3<Real>>) -> Real {
+    let mut pts: Vec<(Real, Real)> = points.into_iter().map(|p| (p.x, p.y)).collect();
+    pts.sort_by(|a, b| a.0.total_cmp(&b.0).then(a.1.total_cmp(&b.1)));
+    pts.dedup();
+    if pts.len() < 3 {
+        return 0.0;
+    }
+    let cross = |o: (Real, Real), a: (Real, Real), b: (Real, Real)| (a.0 - o.0) * (b.1 - o.1) - (a.1 - o.1) * (b.0 - o.0);
+    let mut hull: Vec<(Real, Real)> = Vec::with_capacity(pts.len() * 2);
+    for pass in [pts.clone(), pts.into_iter().rev().collect()] {
+        let floor = hull.len();
+        for p in pass {
+            while hull.len() >= floor + 2 && cross(hull[hull.len() - 2], hull[hull.len() - 1], p) <= 0.0 {
+                hull.pop();
+            }
+            hull.push(p);
+        }
+        // Each chain's last point is the next chain's first.
+        hull.pop();
+    }
+    let n = hull.len();
+    0.5 * (0..n)
+        .map(|i| hull[i].0 * hull[(i + 1) % n].1 - hull[(i + 1) % n].0 * hull[i].1)
+        .sum::<Real>()
+}
+
+/// Maximum distance between an offset arc and the line segments replacing it.
+const ARC_TOLERANCE: Real = 0.005;
+
+/// Offset a closed loop by `distance` to the right of its walking direction.
+/// Convex corners are rounded (arcs tessellated to `ARC_TOLERANCE`); loops that
+/// collapse under the offset disappear, and self-intersecting results may split.
+pub(crate) fn offset_right(seg: &ToolpathSegment, distance: Real) -> Vec<ToolpathSegment> {
+    let verts = loop_vertices(seg);
+    let Some(z) = seg.z() else {
+        return Vec::new();
+    };
+    if verts.len() < 3 || distance == 0.0 {
+        return vec![seg.clone()];
+    }
+    let mut pline: Polyline<Real> = Polyline::with_capacity(verts.len(), true);
+    for p in verts {
+        pline.add(p.x, p.y, 0.0);
+    }
+    // cavalier_contours offsets to the left for positive distances.
+    pline
+        .parallel_offset(-distance)
+        .into_iter()
+        .filter_map(|offset| offset.arcs_to_approx_lines(ARC_TOLERANCE))
+        .map(|lines| ToolpathSegment {
+            points: lines.iter_vertexes().map(|v| Point3::new(v.x, v.y, z)).collect(),
+            ..seg.clone()
+        })
+        .collect()
+}
+
+/// Offset every closed contour of one level by `distance` away from material:
+/// boundaries grow outward and holes shrink inward, using the nesting of the
+/// contours to tell them apart. Open paths pass through unchanged.
+pub(crate) fn offset_away_from_material(segments: &[ToolpathSegment], distance: Real, eps: Real) -> Vec<ToolpathSegment> {
+    let depths = nesting_depths(segments, eps);
+    let mut result = Vec::new();
+    for (seg, depth) in segments.iter().zip(depths) {
+        if !seg.closed || loop_vertices(seg).len() < 3 {
+            result.push(seg.clone());
+            continue;
+        }
+        // Walk with material on the left (boundaries CCW, holes CW) so that
+        // "right" is always away from the part.
+        let mut oriented = seg.clone();
+        if (depth % 2 == 0) != (seg.area_xy_closed() > 0.0) {
+            oriented.points.reverse();
+        }
+        result.extend(offset_right(&oriented, distance));
+    }
+    result
+}
+
+/// Clip a segment to the XY region enclosed by `mask` as a curve: the path is
+/// cut where it crosses the mask boundary and only the runs inside are kept.
+/// No edges are added along the mask, so a loop that crosses it comes back as
+/// open pieces; loops entirely inside are returned unchanged.
+pub(crate) fn clip_to_mask(seg: &ToolpathSegment, mask: &[Point3<Real>]) -> Vec<ToolpathSegment> {
+    let mask_loop = ToolpathSegment {
+        points: mask.to_vec(),
+        closed: true,
+        ..seg.clone()
+    };
+    let edges: Vec<(Point3<Real>, Point3<Real>)> = seg.edges().collect();
+    if edges.is_empty() {
+        let inside = seg.points.first().is_some_and(|p| point_in_loop(p, &mask_loop));
+        return if inside { vec![seg.clone()] } else { Vec::new() };
+    }
+
+    let mut runs: Vec<Vec<Point3<Real>>> = Vec::new();
+    let mut run: Vec<Point3<Real>> = Vec::new();
+    let mut crossed = false;
+    for (a, b) in &edges {
+        let mut cuts = vec![0.0, 1.0];
+        cuts.extend(mask_loop.edges().filter_map(|(c, d)| crossing_param(a, b, &c, &d)));
+        cuts.sort_by(|x, y| x.total_cmp(y));
+        cuts.dedup_by(|x, y| (*x - *y).abs() <= Real::EPSILON);
+        crossed |= cuts.len() > 2;
+        let at = |t: Real| a + (b - a) * t;
+        for span in cuts.windows(2) {
+            if point_in_loop(&at((span[0] + span[1]) * 0.5), &mask_loop) {
+                if run.is_empty() {
+                    run.push(at(span[0]));
+                }
+                run.push(at(span[1]));
+            } else if !run.is_empty() {
+                runs.push(std::mem::take(&mut run));
+            }
+        }
+    }
+    if !run.is_empty() {
+        runs.push(run);
+    }
+
+    if seg.closed && !crossed {
+        // Never crosses the mask: the loop is either wholly inside or wholly outside.
+        return if runs.is_empty() { Vec::new() } else { vec![seg.clone()] };
+    }
+    // A closed loop that starts inside: its last run carries on into its first.
+    let wraps = seg.closed
+        && runs.len() > 1
+        && runs.first().and_then(|r| r.first()) == Some(&edges[0].0)
+        && runs.last().and_then(|r| r.last()) == Some(&edges[edges.len() - 1].1);
+    if wraps {
+        let first = runs.remove(0);
+        runs.last_mut().unwrap().extend(first.into_iter().skip(1));
+    }
+    runs.into_iter()
+        .filter(|run| run.len() >= 2)
+        .map(|points| ToolpathSegment { points, closed: false, ..seg.clone() })
+        .collect()
+}
+
+/// Parameter along `a`-`b` where it properly crosses `c`-`d` in XY, if it does.
+pub(crate) fn crossing_param(a: &Point3<Real>, b: &Point3<Real>, c: &Point3<Real>, d: &Point3<Real>) -> Option<Real> {
+    let (rx, ry) = (b.x - a.x, b.y - a.y);
+    let (sx, sy) = (d.x - c.x, d.y - c.y);
+    let denom = rx * sy - ry * sx;
+    if denom.abs() <= Real::EPSILON {
+        return None;
+    }
+    let t = ((c.x - a.x) * sy - (c.y - a.y) * sx) / denom;
+    let u = ((c.x - a.x) * ry - (c.y - a.y) * rx) / denom;
+    ((0.0..=1.0).contains(&u) && t > 0.0 && t < 1.0).then_some(t)
+}
+
+/// Weld contours of one layer that share boundary edges (e.g. two touching bodies)
+/// into single loops. A wall walked both ways is dropped whether it lies between
+/// two loops or inside one (slicers often return touching bodies as a single
+/// loop running along the interface twice). Only loops made with the same tool and of the same kind
+/// are welded together, and every rebuilt loop keeps the attributes of the
+/// contour it started from. Returns `None` when nothing in the layer was coincident.
+pub(crate) fn weld_layer(segments: &[ToolpathSegment], eps: Real) -> Option<Vec<ToolpathSegment>> {
+    let is_loop = |seg: &ToolpathSegment| seg.closed && loop_vertices(seg).len() >= 3;
+    let mut groups: Vec<Vec<&ToolpathSegment>> = Vec::new();
+    for seg in segments.iter().filter(|seg| is_loop(seg)) {
+        match groups.iter_mut().find(|g| g[0].tool == seg.tool && g[0].kind == seg.kind) {
+            Some(group) => group.push(seg),
+            None => groups.push(vec![seg]),
+        }
+    }
+
+    let mut welded = false;
+    let mut result = Vec::new();
+    for group in groups {
+        match weld_group(&group, eps) {
+            Some(loops) => {
+                welded = true;
+                result.extend(loops);
+            }
+            None => result.extend(group.into_iter().cloned()),
+        }
+    }
+    if !welded {
+        return None;
+    }
+
+    // Anything that wasn't a closed loop (open chains, stray points) passes through untouched.
+    result.extend(segments.iter().filter(|seg| !is_loop(seg)).cloned());
+    Some(result)
+}
+
+/// Weld one group of compatible closed loops, or `None` if no edge of the group
+/// is walked twice.
+fn weld_group(loops: &[&ToolpathSegment], eps: Real) -> Option<Vec<ToolpathSegment>> {
+    let owned: Vec<ToolpathSegment> = loops.iter().map(|seg| (*seg).clone()).collect();
+    let depths = nesting_depths(&owned, eps);
+    // 1) Orient boundaries CCW and holes CW, so shared walls between two solids
+    //    show up as the same edge walked in opposite directions.
+    let mut oriented: Vec<Vec<Point3<Real>>> = Vec::new();
+    for (seg, depth) in owned.iter().zip(&depths) {
+        let mut verts = loop_vertices(seg).to_vec();
+        if (depth % 2 == 0) != (seg.area_xy_closed() > 0.0) {
+            verts.reverse();
+        }
+        oriented.push(verts);
+    }
+
+    // 2) Split every edge at vertices lying on it, so partially
+    //    overlapping walls line up vertex to vertex.
+    let all_vertices: Vec<Point3<Real>> = oriented.iter().flatten().copied().collect();
+    // Each edge remembers which loop it came from, for the rebuilt loop's attributes.
+    let mut edges: Vec<(Point3<Real>, Point3<Real>, usize)> = Vec::new();
+    for (source, verts) in oriented.iter().enumerate() {
+        for i in 0..verts.len() {
+            let (a, b) = (verts[i], verts[(i + 1) % verts.len()]);
+            let mut cuts: Vec<(Real, Point3<Real>)> = all_vertices
+                .iter()
+                .filter(|v| xy_dist(v, &a) > eps && xy_dist(v, &b) > eps)
+                .filter_map(|v| {
+                    let (d, t) = distance_to_edge_xy(v, &a, &b);
+                    (d <= eps).then_some((t, *v))
+                })
+                .collect();
+            cuts.sort_by(|x, y| x.0.total_cmp(&y.0));
+            let mut prev = a;
+            for (_, v) in cuts {
+                if xy_dist(&prev, &v) > eps {
+                    edges.push((prev, v, source));
+                    prev = v;
+                }
+            }
+            edges.push((prev, b, source));
+        }
+    }
+
+    // 3) Cancel opposite pairs (a shared wall, within a loop or between two) and
+    //    drop exact duplicates.
+    let mut alive = vec![true; edges.len()];
+    let mut welded = false;
+    for i in 0..edges.len() {
+        if !alive[i] {
+            continue;
+        }
+        for j in i + 1..edges.len() {
+            if !alive[j] {
+                continue;
+            }
+            let (a, b, _) = edges[i];
+            let (c, d, _) = edges[j];
+            if xy_dist(&a, &d) <= eps && xy_dist(&b, &c) <= eps {
+                alive[i] = false;
+                alive[j] = false;
+                welded = true;
+                break;
+            }
+            if xy_dist(&a, &c) <= eps && xy_dist(&b, &d) <= eps {
+                alive[j] = false;
+                welded = true;
+            }
+        }
+    }
+    if !welded {
+        return None;
+    }
+
+    // 4) Chain the surviving edges back into closed loops.
+    let mut result = Vec::new();
+    for start in 0..edges.len() {
+        if !alive[start] {
+            continue;
+        }
+        alive[start] = false;
+        let mut points = vec![edges[start].0];
+        let mut end = edges[start].1;
+        while xy_dist(&end, &points[0]) > eps {
+            let Some(next) = (0..edges.len()).find(|&k| alive[k] && xy_dist(&edges[k].0, &end) <= eps) else {
+                break;
+            };
+            alive[next] = false;
+            points.push(edges[next].0);
+            end = edges[next].1;
+        }
+        let points = drop_collinear(points, eps);
+        if points.len() >= 3 {
+            result.push(ToolpathSegment { points, ..loops[edges[start].2].clone() });
+        }
+    }
+    Some(result)
+}
+
+/// Remove vertices that sit on the straight line between their neighbours.
+fn drop_collinear(points: Vec<Point3<Real>>, eps: Real) -> Vec<Point3<Real>> {
+    let n = points.len();
+    if n < 4 {
+        return points;
+    }
+    (0..n)
+        .filter(|&i| {
+            let prev = points[(i + n - 1) % n];
+            let next = points[(i + 1) % n];
+            distance_to_edge_xy(&points[i], &prev, &next).0 > eps
+        })
+        .map(|i| points[i])
+        .collect()
+}
+
+fn xy_dist(a: &Point3<Real>, b: &Point3<Real>) -> Real {
+    ((a.x - b.x).powi(2) + (a.y - b.y).powi(2)).sqrt()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tests::square_at;
+    use crate::SegmentKind;
+
+    fn path(points: &[(Real, Real)], closed: bool) -> ToolpathSegment {
+        ToolpathSegment {
+            points: points.iter().map(|&(x, y)| Point3::new(x, y, 0.0)).collect(),
+            kind: SegmentKind::Perimeter,
+            closed,
+            tool: 0,
+            layer_z: Some(0.0),
+        }
+    }
+
+    fn square_mask(size: Real) -> Vec<Point3<Real>> {
+        [(0.0, 0.0), (size, 0.0), (size, size), (0.0, size)]
+            .iter()
+            .map(|&(x, y)| Point3::new(x, y, 0.0))
+            .collect()
+    }
+
+    #[test]
+    fn clip_to_mask_cuts_loops_without_adding_mask_edges() {
+        let loop_across = path(&[(5.0, 5.0), (15.0, 5.0), (15.0, 15.0), (5.0, 15.0)], true);
+        let pieces = clip_to_mask(&loop_across, &square_mask(10.0));
+        assert_eq!(pieces.len(), 1);
+        let piece = &pieces[0];
+        assert!(!piece.closed);
+        let xy: Vec<(Real, Real)> = piece.points.iter().map(|p| (p.x, p.y)).collect();
+        assert_eq!(xy, vec![(5.0, 10.0), (5.0, 5.0), (10.0, 5.0)]);
+    }
+
+    #[test]
+    fn clip_to_mask_keeps_inner_loops_and_drops_outer_ones() {
+        let mask = square_mask(10.0);
+        let inside = path(&[(2.0, 2.0), (8.0, 2.0), (8.0, 8.0)], true);
+        let clipped = clip_to_mask(&inside, &mask);
+        assert_eq!(clipped.len(), 1);
+        assert!(clipped[0].closed);
+        assert_eq!(clipped[0].points, inside.points);
+        let outside = path(&[(20.0, 2.0), (28.0, 2.0), (28.0, 8.0)], true);
+        assert!(clip_to_mask(&outside, &mask).is_empty());
+    }
+
+    fn square_on(tool: usize, x0: Real, y0: Real, size: Real) -> ToolpathSegment {
+        ToolpathSegment { tool, ..square_at(x0, y0, size, 0.0) }
+    }
+
+    #[test]
+    fn weld_layer_merges_abutting_boxes() {
+        let welded = weld_layer(&[square_on(1, 0.0, 0.0, 10.0), square_on(1, 10.0, 0.0, 10.0)], 1e-6).unwrap();
+        assert_eq!(welded.len(), 1);
+        assert_eq!(welded[0].tool, 1);
+        assert!((welded[0].length() - 60.0).abs() < 1e-9);
+        assert!((welded[0].area_xy_closed().abs() - 200.0).abs() < 1e-9);
+    }
+
+    #[test]
+    fn weld_layer_keeps_tools_apart() {
+        let layer = [square_on(0, 0.0, 0.0, 10.0), square_on(1, 10.0, 0.0, 10.0), square_on(2, 50.0, 0.0, 5.0)];
+        assert!(weld_layer(&layer, 1e-6).is_none());
+
+        let layer = [square_on(1, 0.0, 0.0, 10.0), square_on(1, 10.0, 0.0, 10.0), square_on(2, 50.0, 0.0, 5.0)];
+        let welded = weld_layer(&layer, 1e-6).unwrap();
+        let mut tools: Vec<usize> = welded.iter().map(|seg| seg.tool).collect();
+        tools.sort();
+        assert_eq!(tools, vec![1, 2]);
+    }
+
+    #[test]
+    fn clip_to_mask_drops_open_path_outside_mask() {
+        let outside = path(&[(-5.0, 5.0), (-3.0, 5.0)], false);
+        assert!(clip_to_mask(&outside, &square_mask(10.0)).is_empty());
+    }
+}
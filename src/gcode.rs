@@ -0,0 +1,1025 @@
+//! G-code helpers: emitting toolpaths as text and checking text meant for a controller.
+
+use std::collections::{HashMap, HashSet};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use csgrs::float_types::{Real, PI};
+use nalgebra::{Point2, Point3, Vector3};
+
+use crate::contour;
+use crate::motion::{self, MotionLimits, MoveMerging};
+use crate::{CoolingConfig, SegmentKind, ToolpathError, ToolpathSet};
+
+/// Settings for turning a `ToolpathSet` into G-code text.
+#[derive(Debug, Clone)]
+pub struct GcodeConfig {
+    /// Feed used while printing/cutting, in units/min.
+    pub feed_rate: Real,
+    /// Feed attached to travel moves, in units/min.
+    pub travel_rate: Real,
+    /// Acceleration for perimeters and bridges (`M204 S`), if the firmware should be told.
+    pub accel_perimeter: Option<Real>,
+    /// Acceleration for solid and sparse infill (`M204 S`).
+    pub accel_infill: Option<Real>,
+    /// XY jerk limit emitted once at the start (`M205 X Y`).
+    pub jerk: Option<Real>,
+    /// Finish each closed perimeter with a short move of this length toward the
+    /// loop centroid, hiding the seam inside the part instead of on the wall.
+    pub coast_to_center: Option<Real>,
+    /// Length of each approach that is fed at `feed_rate` instead of rapided,
+    /// so the tool decelerates before reaching material. `0.0` disables it.
+    pub rapid_to_feed_margin: Real,
+    /// Emit travels as `G1` at `travel_rate` instead of `G0`, for machines whose
+    /// `G0` moves are uncontrolled.
+    pub rapid_as_g1: bool,
+    /// Start the file with `;` comments recording how it was generated.
+    pub metadata_header: bool,
+    /// The set is a coarse preview (see `AdditiveConfig::preview`); the file
+    /// opens with a comment warning it is not for production.
+    pub preview: bool,
+    /// Name of the generator that produced the set, for the metadata header.
+    pub generator: Option<String>,
+    /// Layer height (additive) or step-down (subtractive) the set was generated with.
+    pub layer_height: Option<Real>,
+    /// Tool or nozzle description, for the metadata header.
+    pub tool: Option<String>,
+    /// Raw G-code emitted before the first move of each run of a segment kind,
+    /// e.g. to switch pressure advance for bridges.
+    pub kind_prologue: HashMap<SegmentKind, String>,
+    /// Filament extrusion settings. Without them only motion is emitted (e.g. for CNC).
+    pub extrusion: Option<ExtrusionConfig>,
+    /// Slow down into and out of sharp corners with explicit split moves, for
+    /// firmware without its own lookahead. Each corner gets a short move on either
+    /// side fed at the corner speed these limits allow.
+    pub corner_smoothing: Option<MotionLimits>,
+    /// Merge runs of moves too short to brake within into single moves.
+    pub merge_moves: Option<MoveMerging>,
+    /// Minimum layer time enforcement: each layer's feed is scaled and the part
+    /// fan (`M106`) set as `CoolingConfig::cooling_for` decides.
+    pub cooling: Option<CoolingConfig>,
+    /// Park the tool once the job is done.
+    pub park: Option<ParkConfig>,
+    /// Machine work volume. When set, every coordinate must lie within
+    /// `[0, bed_size]` on each axis or nothing is written.
+    pub bed_size: Option<Vector3<Real>>,
+}
+
+/// How much filament to push for each move and how to retract around travels.
+#[derive(Debug, Clone)]
+pub struct ExtrusionConfig {
+    pub filament_diameter: Real,
+    /// Width of the extruded bead. Its height is `GcodeConfig::layer_height`
+    /// (half the width when that isn't set).
+    pub line_width: Real,
+    /// Filament pulled back before each travel; `0.0` disables retraction.
+    pub retract_length: Real,
+    /// Feed for retract and unretract moves, in units/min.
+    pub retract_rate: Real,
+    /// Extra volume (mm³) added to the first extruding move after a retraction,
+    /// to make up for the ooze lost while travelling.
+    pub extra_prime_volume: Real,
+    /// Only retract for travels that leave the layer's printed region (the area
+    /// inside its closed contours); travels staying over material ooze onto the
+    /// part where it doesn't show, so they skip the retraction.
+    pub retract_only_over_gaps: bool,
+}
+
+impl Default for ExtrusionConfig {
+    fn default() -> Self {
+        ExtrusionConfig {
+            filament_diameter: 1.75,
+            line_width: 0.4,
+            retract_length: 0.8,
+            retract_rate: 2100.0,
+            extra_prime_volume: 0.0,
+            retract_only_over_gaps: false,
+        }
+    }
+}
+
+impl ExtrusionConfig {
+    pub(crate) fn filament_area(&self) -> Real {
+        PI * (self.filament_diameter * 0.5).powi(2)
+    }
+
+    /// Filament length per unit of path for a bead `layer_height` tall.
+    pub(crate) fn e_per_unit(&self, layer_height: Option<Real>) -> Real {
+        let height = layer_height.unwrap_or(self.line_width * 0.5);
+        self.line_width * height / self.filament_area()
+    }
+}
+
+/// Where to leave the tool at the end of a job.
+#[derive(Debug, Clone)]
+pub struct ParkConfig {
+    /// How far to raise Z above the last position before moving sideways.
+    pub lift: Real,
+    /// XY position to park at, reached at the raised height.
+    pub position: Point2<Real>,
+    /// Finish with `G28` to home all axes.
+    pub home: bool,
+}
+
+impl Default for ParkConfig {
+    fn default() -> Self {
+        ParkConfig {
+            lift: 10.0,
+            position: Point2::origin(),
+            home: false,
+        }
+    }
+}
+
+impl Default for GcodeConfig {
+    fn default() -> Self {
+        GcodeConfig {
+            feed_rate: 1200.0,
+            travel_rate: 6000.0,
+            accel_perimeter: None,
+            accel_infill: None,
+            jerk: None,
+            coast_to_center: None,
+            rapid_to_feed_margin: 0.0,
+            rapid_as_g1: false,
+            metadata_header: false,
+            preview: false,
+            generator: None,
+            layer_height: None,
+            tool: None,
+            kind_prologue: HashMap::new(),
+            extrusion: None,
+            corner_smoothing: None,
+            merge_moves: None,
+            cooling: None,
+            park: None,
+            bed_size: None,
+        }
+    }
+}
+
+/// Emit `set` as G-code: one travel to the start of each segment, then feed moves
+/// along it. Closed loops are walked back to their first point. Coordinates are
+/// written as-is in absolute machine coordinates. With `cfg.bed_size` set, the
+/// set's points are checked up front and every emitted move (approaches, coasts,
+/// parking) as it is written; the first one outside the bed fails the export.
+pub fn to_gcode(set: &ToolpathSet, cfg: &GcodeConfig) -> Result<String, ToolpathError> {
+    if let Some(bed_size) = cfg.bed_size {
+        check_bed_bounds(set, bed_size)?;
+    }
+
+    let mut w = Writer::new(cfg);
+    if cfg.preview {
+        w.line("; PREVIEW - not for production");
+    }
+    if cfg.metadata_header {
+        w.metadata_header();
+    }
+    w.line("G21 ; millimeters");
+    w.line("G90 ; absolute positioning");
+    if cfg.extrusion.is_some() {
+        w.line("M83 ; relative extrusion");
+    }
+    if let Some(jerk) = cfg.jerk {
+        w.line(&format!("M205 X{} Y{}", fmt(jerk), fmt(jerk)));
+    }
+
+    let mut previous_kind = None;
+    let mut layer_start = None;
+    let mut layer_loops = None;
+    let layers = set.layer_ranges();
+    for (range, seg) in layers.iter().flat_map(|range| set.segments[range.clone()].iter().map(move |seg| (range, seg))) {
+        if layer_start != Some(range.start) {
+            layer_start = Some(range.start);
+            layer_loops = cfg
+                .extrusion
+                .as_ref()
+                .filter(|e| e.retract_only_over_gaps)
+                .map(|_| LayerLoops::new(&set.segments[range.clone()]));
+            if let Some(cooling) = &cfg.cooling {
+                w.cool_layer(cooling, &set.segments[range.clone()]);
+            }
+        }
+        let Some(start) = seg.points.first() else {
+            continue;
+        };
+
+        if w.tool != Some(seg.tool) && (w.tool.is_some() || seg.tool != 0) {
+            w.line(&format!("T{}", seg.tool));
+        }
+        w.tool = Some(seg.tool);
+
+        if previous_kind != Some(seg.kind) {
+            if let Some(snippet) = cfg.kind_prologue.get(&seg.kind) {
+                w.line(snippet.trim_end());
+            }
+            previous_kind = Some(seg.kind);
+        }
+
+        // Switch acceleration only when the feature type actually changes it.
+        let accel = match seg.kind {
+            SegmentKind::Perimeter | SegmentKind::Bridge => cfg.accel_perimeter,
+            SegmentKind::SolidInfill | SegmentKind::Infill | SegmentKind::PurgeTower => cfg.accel_infill,
+        };
+        if let Some(value) = accel.filter(|_| accel != w.accel) {
+            w.line(&format!("M204 S{}", fmt(value)));
+            w.accel = accel;
+        }
+
+        let over_gap = match (&cfg.extrusion, &layer_loops) {
+            (Some(e), Some(loops)) => w.position.is_none_or(|from| loops.crosses_gap(&from, start, e.line_width)),
+            _ => true,
+        };
+        w.travel(start, over_gap);
+        let mut path: Vec<Point3<Real>> = std::iter::once(*start).chain(seg.edges().map(|(_, to)| to)).collect();
+        if let Some(merging) = &cfg.merge_moves {
+            path = motion::merge_tiny_moves(&path, w.layer_feed_rate(), merging);
+        }
+        match &cfg.corner_smoothing {
+            Some(limits) => w.feed_cornering(&path, limits),
+            None => {
+                for to in &path[1..] {
+                    w.feed(to, true, None);
+                }
+            }
+        }
+
+        if let (Some(distance), SegmentKind::Perimeter) = (cfg.coast_to_center, seg.kind) {
+            if let Some(coast) = coast_point(seg, distance) {
+                w.feed(&coast, false, Some("coast"));
+            }
+        }
+    }
+
+    if let Some(park) = &cfg.park {
+        w.park(park);
+    }
+    match w.out_of_bounds {
+        Some(err) => Err(err),
+        None => Ok(w.out),
+    }
+}
+
+/// First point outside `[0, bed_size]`, as an error.
+fn check_bed_bounds(set: &ToolpathSet, bed_size: Vector3<Real>) -> Result<(), ToolpathError> {
+    match set.segments.iter().flat_map(|seg| &seg.points).find_map(|p| outside_bed(p, bed_size)) {
+        Some(err) => Err(err),
+        None => Ok(()),
+    }
+}
+
+/// `p` as an `OutOfBounds` error if it lies outside `[0, bed_size]`.
+fn outside_bed(p: &Point3<Real>, bed_size: Vector3<Real>) -> Option<ToolpathError> {
+    let tolerance = 1e-9;
+    let inside = (0..3).all(|axis| p[axis] >= -tolerance && p[axis] <= bed_size[axis] + tolerance);
+    (!inside).then_some(ToolpathError::OutOfBounds { point: *p, bed_size })
+}
+
+/// Modal state of the machine while text is being emitted.
+struct Writer<'a> {
+    cfg: &'a GcodeConfig,
+    out: String,
+    position: Option<Point3<Real>>,
+    feed_rate: Option<Real>,
+    accel: Option<Real>,
+    tool: Option<usize>,
+    /// Filament length still owed to the next extruding move (unretract prime).
+    pending_prime: Real,
+    /// First emitted move found outside `cfg.bed_size`.
+    out_of_bounds: Option<ToolpathError>,
+    /// Multiplier on `cfg.feed_rate` for the current layer (minimum layer time).
+    feed_scale: Real,
+    fan: Option<Real>,
+}
+
+impl<'a> Writer<'a> {
+    fn new(cfg: &'a GcodeConfig) -> Self {
+        Writer {
+            cfg,
+            out: String::new(),
+            position: None,
+            feed_rate: None,
+            accel: None,
+            tool: None,
+            pending_prime: 0.0,
+            out_of_bounds: None,
+            feed_scale: 1.0,
+            fan: None,
+        }
+    }
+
+    fn line(&mut self, text: &str) {
+        self.out.push_str(text);
+        self.out.push('\n');
+    }
+
+    /// `;` comments documenting the settings the file was generated with.
+    fn metadata_header(&mut self) {
+        let cfg = self.cfg;
+        self.line(&format!("; ironpath {}", env!("CARGO_PKG_VERSION")));
+        if let Ok(now) = SystemTime::now().duration_since(UNIX_EPOCH) {
+            self.line(&format!("; generated_at: {} (unix seconds)", now.as_secs()));
+        }
+        if let Some(generator) = &cfg.generator {
+            self.line(&format!("; generator: {}", generator));
+        }
+        if let Some(layer_height) = cfg.layer_height {
+            self.line(&format!("; layer_height: {}", fmt(layer_height)));
+        }
+        if let Some(tool) = &cfg.tool {
+            self.line(&format!("; tool: {}", tool));
+        }
+        self.line(&format!("; feed_rate: {}", fmt(cfg.feed_rate)));
+        self.line(&format!("; travel_rate: {}", fmt(cfg.travel_rate)));
+        for (name, value) in [
+            ("accel_perimeter", cfg.accel_perimeter),
+            ("accel_infill", cfg.accel_infill),
+            ("jerk", cfg.jerk),
+            ("coast_to_center", cfg.coast_to_center),
+        ] {
+            if let Some(value) = value {
+                self.line(&format!("; {}: {}", name, fmt(value)));
+            }
+        }
+        if cfg.rapid_to_feed_margin > 0.0 {
+            self.line(&format!("; rapid_to_feed_margin: {}", fmt(cfg.rapid_to_feed_margin)));
+        }
+    }
+
+    /// Rapid to `to`. The last `rapid_to_feed_margin` of the approach is taken at
+    /// cutting feed so the tool never meets material at rapid speed; with no known
+    /// position the approach comes straight down from above.
+    /// Retraction is skipped when `over_gap` is false.
+    fn travel(&mut self, to: &Point3<Real>, over_gap: bool) {
+        let moving = over_gap && self.position.is_some_and(|p| xyz(&p) != xyz(to));
+        let retract = self.cfg.extrusion.as_ref().map_or(0.0, |e| e.retract_length);
+        if moving && retract > 0.0 {
+            self.extrude_only(-retract);
+        }
+        self.approach(to);
+        if moving && retract > 0.0 {
+            self.extrude_only(retract);
+            if let Some(extrusion) = &self.cfg.extrusion {
+                self.pending_prime = extrusion.extra_prime_volume / extrusion.filament_area();
+            }
+        }
+    }
+
+    /// Retract if extruding, raise Z by `park.lift`, rapid to the park position
+    /// and optionally home.
+    fn park(&mut self, park: &ParkConfig) {
+        let retract = self.cfg.extrusion.as_ref().map_or(0.0, |e| e.retract_length);
+        if self.position.is_some() && retract > 0.0 {
+            self.extrude_only(-retract);
+        }
+        let z = self.position.map_or(0.0, |p| p.z) + park.lift;
+        if let Some(p) = self.position {
+            self.rapid(&Point3::new(p.x, p.y, z));
+        }
+        self.rapid(&Point3::new(park.position.x, park.position.y, z));
+        if park.home {
+            self.line("G28 ; home");
+        }
+    }
+
+    /// Retract (negative) or unretract (positive) without moving.
+    fn extrude_only(&mut self, length: Real) {
+        let rate = self.cfg.extrusion.as_ref().map_or(self.cfg.feed_rate, |e| e.retract_rate);
+        let f = self.feed_word(rate);
+        self.line(&format!("G1 E{}{}", fmt_e(length), f));
+    }
+
+    fn approach(&mut self, to: &Point3<Real>) {
+        let margin = self.cfg.rapid_to_feed_margin;
+        if margin <= 0.0 {
+            self.rapid(to);
+            return;
+        }
+        let from = self.position.unwrap_or(Point3::new(to.x, to.y, to.z + margin * 2.0));
+        let distance = (to - from).norm();
+        if distance <= Real::EPSILON {
+            return;
+        }
+        if distance > margin {
+            let slow_down = to + (from - to) * (margin / distance);
+            self.rapid(&slow_down);
+        } else if self.position.is_none() {
+            self.rapid(&from);
+        }
+        self.feed(to, false, None);
+    }
+
+    fn rapid(&mut self, to: &Point3<Real>) {
+        let Some(axes) = self.axis_words(to) else {
+            return;
+        };
+        self.check_bounds(to);
+        let f = self.feed_word(self.cfg.travel_rate);
+        let code = if self.cfg.rapid_as_g1 { "G1" } else { "G0" };
+        self.line(&format!("{} {}{}", code, axes, f));
+        self.position = Some(*to);
+    }
+
+    /// Feed along `path`, splitting off the stretch before and after each corner
+    /// where the machine has to be below cruise speed. Those stretches are as
+    /// long as it takes to brake from `feed_rate` to the corner speed, capped at
+    /// half the edge, and are fed at the corner speed.
+    fn feed_cornering(&mut self, path: &[Point3<Real>], limits: &MotionLimits) {
+        let speed = self.layer_feed_rate() / 60.0;
+        let moves: Vec<motion::Move> = path
+            .windows(2)
+            .filter(|w| (w[1] - w[0]).norm() > Real::EPSILON)
+            .map(|w| motion::Move { from: w[0], to: w[1], speed })
+            .collect();
+        let mut corner = vec![speed; moves.len() + 1];
+        for i in 1..moves.len() {
+            corner[i] = motion::junction_speed(&moves[i - 1], &moves[i], limits);
+        }
+        let slow_length = |v: Real| {
+            if v >= speed || limits.max_accel <= 0.0 {
+                0.0
+            } else {
+                (speed * speed - v * v) / (2.0 * limits.max_accel)
+            }
+        };
+        for (i, m) in moves.iter().enumerate() {
+            let length = (m.to - m.from).norm();
+            let direction = (m.to - m.from) / length;
+            let leaving = slow_length(corner[i]).min(length * 0.5);
+            let approaching = slow_length(corner[i + 1]).min(length * 0.5);
+            if leaving > 0.0 {
+                self.feed_at(&(m.from + direction * leaving), true, corner[i] * 60.0, Some("corner"));
+            }
+            self.feed(&(m.to - direction * approaching), true, None);
+            if approaching > 0.0 {
+                self.feed_at(&m.to, true, corner[i + 1] * 60.0, Some("corner"));
+            }
+        }
+    }
+
+    /// Feed move to `to`, extruding along it when `extrude` is set and extrusion
+    /// is configured.
+    fn feed(&mut self, to: &Point3<Real>, extrude: bool, comment: Option<&str>) {
+        self.feed_at(to, extrude, self.layer_feed_rate(), comment);
+    }
+
+    /// `cfg.feed_rate` slowed down for the current layer, if cooling asks for it.
+    fn layer_feed_rate(&self) -> Real {
+        self.cfg.feed_rate * self.feed_scale
+    }
+
+    /// Apply minimum layer time to the layer made of `layer`: scale its feed and
+    /// set the fan (only emitted when the speed changes).
+    fn cool_layer(&mut self, cooling: &CoolingConfig, layer: &[crate::ToolpathSegment]) {
+        let length: Real = layer.iter().map(|seg| seg.length()).sum();
+        let z = layer.first().and_then(|seg| seg.layer()).unwrap_or(0.0);
+        let plan = cooling.cooling_for(z, length / self.cfg.feed_rate * 60.0);
+        self.feed_scale = plan.feed_scale;
+        let fan = (plan.fan.clamp(0.0, 1.0) * 255.0).round();
+        if self.fan != Some(fan) {
+            self.line(&format!("M106 S{}", fmt(fan)));
+            self.fan = Some(fan);
+        }
+    }
+
+    /// `feed` at an explicit `rate` instead of `feed_rate`.
+    fn feed_at(&mut self, to: &Point3<Real>, extrude: bool, rate: Real, comment: Option<&str>) {
+        let Some(axes) = self.axis_words(to) else {
+            return;
+        };
+        self.check_bounds(to);
+        let e = match (&self.cfg.extrusion, self.position) {
+            (Some(extrusion), Some(from)) if extrude => {
+                let length = (to - from).norm() * extrusion.e_per_unit(self.cfg.layer_height);
+                let e = length + std::mem::take(&mut self.pending_prime);
+                format!(" E{}", fmt_e(e))
+            }
+            _ => String::new(),
+        };
+        let f = self.feed_word(rate);
+        let comment = comment.map(|c| format!(" ; {}", c)).unwrap_or_default();
+        self.line(&format!("G1 {}{}{}{}", axes, e, f, comment));
+        self.position = Some(*to);
+    }
+
+    /// Remember the first move target outside `cfg.bed_size`.
+    fn check_bounds(&mut self, to: &Point3<Real>) {
+        if let Some(bed_size) = self.cfg.bed_size {
+            if self.out_of_bounds.is_none() {
+                self.out_of_bounds = outside_bed(to, bed_size);
+            }
+        }
+    }
+
+    /// Only the axes whose emitted value differs from the current position, or
+    /// `None` when the move wouldn't change anything.
+    fn axis_words(&self, to: &Point3<Real>) -> Option<String> {
+        let Some(from) = self.position else {
+            return Some(xyz(to));
+        };
+        let words: Vec<String> = [('X', from.x, to.x), ('Y', from.y, to.y), ('Z', from.z, to.z)]
+            .iter()
+            .filter(|(_, a, b)| fmt(*a) != fmt(*b))
+            .map(|(axis, _, b)| format!("{}{}", axis, fmt(*b)))
+            .collect();
+        (!words.is_empty()).then(|| words.join(" "))
+    }
+
+    /// ` F<rate>` when the feed changes, otherwise nothing (F is modal).
+    fn feed_word(&mut self, rate: Real) -> String {
+        if self.feed_rate == Some(rate) {
+            return String::new();
+        }
+        self.feed_rate = Some(rate);
+        format!(" F{}", fmt(rate))
+    }
+}
+
+/// The closed loops of one layer with their XY bounds, built once per layer so
+/// travels only look at the loops they can reach.
+pub(crate) struct LayerLoops<'a> {
+    loops: Vec<(&'a crate::ToolpathSegment, [Real; 4])>,
+}
+
+impl<'a> LayerLoops<'a> {
+    pub(crate) fn new(layer: &'a [crate::ToolpathSegment]) -> Self {
+        let loops = layer
+            .iter()
+            .filter(|seg| seg.closed && !seg.points.is_empty())
+            .map(|seg| {
+                let bounds = seg.points.iter().fold([Real::MAX, Real::MAX, Real::MIN, Real::MIN], |b, p| {
+                    [b[0].min(p.x), b[1].min(p.y), b[2].max(p.x), b[3].max(p.y)]
+                });
+                (seg, bounds)
+            })
+            .collect();
+        LayerLoops { loops }
+    }
+
+    /// Loops whose bounds, grown by `margin`, overlap the XY box spanned by `a` and `b`.
+    fn near(
+        &self,
+        a: &Point3<Real>,
+        b: &Point3<Real>,
+        margin: Real,
+    ) -> impl Iterator<Item = &'a crate::ToolpathSegment> + '_ {
+        let (min_x, max_x) = (a.x.min(b.x) - margin, a.x.max(b.x) + margin);
+        let (min_y, max_y) = (a.y.min(b.y) - margin, a.y.max(b.y) + margin);
+        self.loops
+            .iter()
+            .filter(move |(_, [x0, y0, x1, y1])| *x0 <= max_x && *x1 >= min_x && *y0 <= max_y && *y1 >= min_y)
+            .map(|(seg, _)| *seg)
+    }
+
+    /// Whether the straight travel from `from` to `to` leaves the material enclosed
+    /// by the loops. The travel is cut where it crosses a contour and each span is
+    /// judged by its midpoint; spans within half `spacing` of a contour count as
+    /// material, since travels start and end on one.
+    pub(crate) fn crosses_gap(&self, from: &Point3<Real>, to: &Point3<Real>, spacing: Real) -> bool {
+        let mut cuts = vec![0.0, 1.0];
+        for seg in self.near(from, to, 0.0) {
+            cuts.extend(seg.edges().filter_map(|(a, b)| contour::crossing_param(from, to, &a, &b)));
+        }
+        cuts.sort_by(|x, y| x.total_cmp(y));
+        cuts.dedup_by(|x, y| (*x - *y).abs() <= Real::EPSILON);
+        cuts.windows(2).any(|span| {
+            let p = from + (to - from) * ((span[0] + span[1]) * 0.5);
+            let enclosing = self.near(&p, &p, 0.0).filter(|seg| contour::point_in_loop(&p, seg)).count();
+            let on_contour =
+                self.near(&p, &p, spacing * 0.5).any(|seg| contour::on_loop_boundary(&p, seg, spacing * 0.5));
+            enclosing % 2 == 0 && !on_contour
+        })
+    }
+}
+
+/// Point `distance` from the end of a closed loop toward its centroid (never past it).
+fn coast_point(seg: &crate::ToolpathSegment, distance: Real) -> Option<Point3<Real>> {
+    if !seg.closed || contour::loop_vertices(seg).len() < 3 {
+        return None;
+    }
+    let end = seg.points[0];
+    let (cx, cy) = contour::centroid_xy(seg)?;
+    let (dx, dy) = (cx - end.x, cy - end.y);
+    let to_center = (dx * dx + dy * dy).sqrt();
+    if to_center <= Real::EPSILON {
+        return None;
+    }
+    let step = distance.min(to_center) / to_center;
+    Some(Point3::new(end.x + dx * step, end.y + dy * step, end.z))
+}
+
+fn xyz(p: &Point3<Real>) -> String {
+    format!("X{} Y{} Z{}", fmt(p.x), fmt(p.y), fmt(p.z))
+}
+
+/// Format a number with up to three decimals and no trailing zeros.
+fn fmt(value: Real) -> String {
+    fmt_decimals(value, 3)
+}
+
+/// Extrusion lengths are small, so they keep five decimals.
+fn fmt_e(value: Real) -> String {
+    fmt_decimals(value, 5)
+}
+
+fn fmt_decimals(value: Real, decimals: usize) -> String {
+    let s = format!("{:.*}", decimals, value);
+    let s = s.trim_end_matches('0').trim_end_matches('.');
+    if s == "-0" { "0".to_string() } else { s.to_string() }
+}
+
+/// A `G`/`M` command found in G-code text that the target controller does not accept.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct UnsupportedCode {
+    /// 1-based line number in the checked text.
+    pub line: usize,
+    /// Normalized command word, e.g. `G5` or `M600`.
+    pub code: String,
+}
+
+/// Check every `G`/`M` word in `text` against `allowed_codes`, reporting the ones
+/// that are missing. Codes are normalized before lookup (`G01` -> `G1`), so the
+/// whitelist should hold the short form.
+pub fn validate_gcode(text: &str, allowed_codes: &HashSet<String>) -> Vec<UnsupportedCode> {
+    let mut unsupported = Vec::new();
+    for (index, line) in text.lines().enumerate() {
+        for code in command_words(line) {
+            if !allowed_codes.contains(&code) {
+                unsupported.push(UnsupportedCode { line: index + 1, code });
+            }
+        }
+    }
+    unsupported
+}
+
+/// Extract the normalized `G`/`M` words of a single line, ignoring comments.
+fn command_words(line: &str) -> Vec<String> {
+    // Drop `;` comments first, then skip over `( ... )` comments while scanning.
+    let line = line.split(';').next().unwrap_or("");
+    let mut words = Vec::new();
+    let mut chars = line.chars().peekable();
+    let mut in_paren = false;
+
+    while let Some(c) = chars.next() {
+        match c {
+            '(' => in_paren = true,
+            ')' => in_paren = false,
+            _ if in_paren => {}
+            'G' | 'g' | 'M' | 'm' => {
+                let mut number = String::new();
+                while let Some(&d) = chars.peek() {
+                    if d.is_ascii_digit() || d == '.' {
+                        number.push(d);
+                        chars.next();
+                    } else {
+                        break;
+                    }
+                }
+                if !number.is_empty() {
+                    words.push(format!("{}{}", c.to_ascii_uppercase(), normalize_number(&number)));
+                }
+            }
+            _ => {}
+        }
+    }
+    words
+}
+
+/// Strip leading zeros from the integer part of a command number (`01` -> `1`, `90.1` stays).
+fn normalize_number(number: &str) -> &str {
+    let trimmed = number.trim_start_matches('0');
+    if trimmed.len() == number.len() {
+        // No leading zeros (this also covers `.5` and a bare `.`).
+        number
+    } else if trimmed.is_empty() || trimmed.starts_with('.') {
+        &number[number.len() - trimmed.len() - 1..]
+    } else {
+        trimmed
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tests::{square, square_at};
+    use crate::ToolpathSegment;
+
+    fn allowed(codes: &[&str]) -> HashSet<String> {
+        codes.iter().map(|c| c.to_string()).collect()
+    }
+
+    fn path(points: &[(Real, Real, Real)], closed: bool) -> ToolpathSegment {
+        ToolpathSegment {
+            points: points.iter().map(|&(x, y, z)| Point3::new(x, y, z)).collect(),
+            kind: SegmentKind::Perimeter,
+            closed,
+            tool: 0,
+            layer_z: None,
+        }
+    }
+
+    fn set(segments: Vec<ToolpathSegment>) -> ToolpathSet {
+        ToolpathSet { segments }
+    }
+
+    #[test]
+    fn bed_bounds_accept_paths_inside_the_bed() {
+        let cfg = GcodeConfig { bed_size: Some(Vector3::new(10.0, 10.0, 5.0)), ..Default::default() };
+        assert!(to_gcode(&set(vec![square_at(1.0, 1.0, 8.0, 1.0)]), &cfg).is_ok());
+    }
+
+    #[test]
+    fn bed_bounds_reject_points_outside_the_bed() {
+        let cfg = GcodeConfig { bed_size: Some(Vector3::new(10.0, 10.0, 5.0)), ..Default::default() };
+        let err = to_gcode(&set(vec![square_at(5.0, 5.0, 8.0, 1.0)]), &cfg).unwrap_err();
+        assert_eq!(
+            err,
+            ToolpathError::OutOfBounds { point: Point3::new(13.0, 5.0, 1.0), bed_size: Vector3::new(10.0, 10.0, 5.0) }
+        );
+    }
+
+    #[test]
+    fn bed_bounds_cover_generated_moves() {
+        // The approach starts above the first point, higher than the bed allows.
+        let cfg = GcodeConfig {
+            bed_size: Some(Vector3::new(10.0, 10.0, 5.0)),
+            rapid_to_feed_margin: 3.0,
+            ..Default::default()
+        };
+        let err = to_gcode(&set(vec![square_at(1.0, 1.0, 8.0, 4.0)]), &cfg).unwrap_err();
+        assert!(matches!(err, ToolpathError::OutOfBounds { point, .. } if point.z > 5.0));
+    }
+
+    #[test]
+    fn park_footer_lifts_and_moves_when_configured() {
+        let segments = set(vec![square_at(1.0, 1.0, 8.0, 1.0)]);
+        let plain = to_gcode(&segments, &GcodeConfig::default()).unwrap();
+        assert!(plain.ends_with("G1 Y1\n"));
+
+        let cfg = GcodeConfig {
+            park: Some(ParkConfig { lift: 10.0, position: Point2::new(0.0, 20.0), home: true }),
+            ..Default::default()
+        };
+        let parked = to_gcode(&segments, &cfg).unwrap();
+        assert!(parked.ends_with("G1 Y1\nG0 Z11 F6000\nG0 X0 Y20\nG28 ; home\n"));
+    }
+
+    #[test]
+    fn park_respects_bed_bounds() {
+        let cfg = GcodeConfig {
+            park: Some(ParkConfig { position: Point2::new(500.0, 500.0), ..Default::default() }),
+            bed_size: Some(Vector3::new(10.0, 10.0, 20.0)),
+            ..Default::default()
+        };
+        let err = to_gcode(&set(vec![square_at(1.0, 1.0, 8.0, 1.0)]), &cfg).unwrap_err();
+        assert!(matches!(err, ToolpathError::OutOfBounds { point, .. } if point.x == 500.0));
+    }
+
+    #[test]
+    fn cooling_slows_short_layers_and_sets_the_fan() {
+        use crate::MinLayerTimeMode;
+        // A 40 mm layer at 1200 mm/min takes 2 s; the 10 s minimum slows it to the
+        // 30% floor with the fan part way up, while the long layer runs as-is.
+        let short = square(1.0);
+        let long = square_at(0.0, 0.0, 100.0, 2.0);
+        let cfg = GcodeConfig {
+            cooling: Some(CoolingConfig {
+                min_layer_time: 10.0,
+                mode: MinLayerTimeMode::Feed,
+                min_feed_scale: 0.3,
+                fan_min: 0.0,
+                fan_max: 1.0,
+            }),
+            ..Default::default()
+        };
+        let gcode = to_gcode(&set(vec![short, long]), &cfg).unwrap();
+        let lines: Vec<&str> = gcode.lines().collect();
+        assert_eq!(lines[2], "M106 S85");
+        assert_eq!(lines[4], "G1 X10 F360");
+        let fan_off = lines.iter().position(|l| *l == "M106 S0").unwrap();
+        assert!(lines[fan_off..].iter().any(|l| l.ends_with("F1200")));
+    }
+
+    #[test]
+    fn merge_moves_emits_fewer_feed_moves() {
+        let fine: Vec<(Real, Real, Real)> = (0..=200).map(|i| (i as Real * 0.1, 0.0005 * (i % 2) as Real, 1.0)).collect();
+        let segments = set(vec![path(&fine, false)]);
+        let count = |cfg: &GcodeConfig| to_gcode(&segments, cfg).unwrap().lines().filter(|l| l.starts_with("G1")).count();
+        let merged = GcodeConfig {
+            merge_moves: Some(MoveMerging { limits: MotionLimits::default(), tolerance: 0.01 }),
+            ..Default::default()
+        };
+        assert_eq!(count(&GcodeConfig::default()), 200);
+        assert!(count(&merged) < 20);
+    }
+
+    #[test]
+    fn retract_only_over_gaps_retracts_for_the_gap_crossing_travel() {
+        let layer = || {
+            vec![square(0.2), square_at(2.0, 2.0, 6.0, 0.2), square_at(20.0, 0.0, 10.0, 0.2)]
+        };
+        let retractions = |over_gaps_only: bool| {
+            let cfg = GcodeConfig {
+                extrusion: Some(ExtrusionConfig { retract_only_over_gaps: over_gaps_only, ..Default::default() }),
+                ..Default::default()
+            };
+            let gcode = to_gcode(&set(layer()), &cfg).unwrap();
+            gcode.lines().filter(|l| l.starts_with("G1 E-")).count()
+        };
+        assert_eq!(retractions(false), 2);
+        assert_eq!(retractions(true), 1);
+    }
+
+    #[test]
+    fn retract_only_over_gaps_retracts_across_a_hole() {
+        // A 20x20 plate with a hole at y 3..7; infill ends at (1, 5) and resumes at (9, 5).
+        let layer = |hole_x: Real| {
+            let mut hole = square_at(hole_x, 3.0, 4.0, 0.2);
+            hole.points.reverse();
+            let infill = |points: &[(Real, Real, Real)]| ToolpathSegment { kind: SegmentKind::Infill, ..path(points, false) };
+            set(vec![
+                square_at(0.0, 0.0, 20.0, 0.2),
+                hole,
+                infill(&[(1.0, 1.0, 0.2), (1.0, 5.0, 0.2)]),
+                infill(&[(9.0, 5.0, 0.2), (9.0, 9.0, 0.2)]),
+            ])
+        };
+        let cfg = GcodeConfig {
+            extrusion: Some(ExtrusionConfig { retract_only_over_gaps: true, ..Default::default() }),
+            ..Default::default()
+        };
+        let retractions = |segments: &ToolpathSet| {
+            let gcode = to_gcode(segments, &cfg).unwrap();
+            gcode.lines().filter(|l| l.starts_with("G1 E-")).count()
+        };
+        assert_eq!(retractions(&layer(3.0)), 1);
+        // Moved out of the way, the hole no longer lies under the travel.
+        assert_eq!(retractions(&layer(12.0)), 0);
+    }
+
+    #[test]
+    fn acceleration_switches_only_when_the_feature_changes_it() {
+        let infill = |x: Real| ToolpathSegment { kind: SegmentKind::Infill, ..path(&[(x, 0.0, 1.0), (x, 5.0, 1.0)], false) };
+        let bridge = ToolpathSegment { kind: SegmentKind::Bridge, ..path(&[(0.0, 20.0, 1.0), (5.0, 20.0, 1.0)], false) };
+        let cfg = GcodeConfig {
+            accel_perimeter: Some(500.0),
+            accel_infill: Some(3000.0),
+            jerk: Some(8.0),
+            ..Default::default()
+        };
+        let gcode = to_gcode(&set(vec![square(1.0), infill(2.0), infill(4.0), bridge]), &cfg).unwrap();
+        let accel: Vec<&str> = gcode.lines().filter(|l| l.starts_with("M20")).collect();
+        assert_eq!(accel, vec!["M205 X8 Y8", "M204 S500", "M204 S3000", "M204 S500"]);
+    }
+
+    #[test]
+    fn coast_moves_toward_the_loop_centroid() {
+        let cfg = GcodeConfig { coast_to_center: Some(2.0), ..Default::default() };
+        let gcode = to_gcode(&set(vec![square(1.0)]), &cfg).unwrap();
+        assert!(gcode.ends_with("G1 Y0\nG1 X1.414 Y1.414 ; coast\n"), "{gcode}");
+
+        // Never past the centroid, and open paths don't coast.
+        let cfg = GcodeConfig { coast_to_center: Some(100.0), ..Default::default() };
+        let gcode = to_gcode(&set(vec![square(1.0)]), &cfg).unwrap();
+        assert!(gcode.ends_with("G1 X5 Y5 ; coast\n"));
+        let open = to_gcode(&set(vec![path(&[(0.0, 0.0, 1.0), (10.0, 0.0, 1.0)], false)]), &cfg).unwrap();
+        assert!(!open.contains("coast"));
+    }
+
+    #[test]
+    fn approach_feeds_the_final_margin() {
+        let cfg = GcodeConfig { rapid_to_feed_margin: 2.0, ..Default::default() };
+        let gcode = to_gcode(&set(vec![square(1.0), square_at(20.0, 0.0, 10.0, 1.0)]), &cfg).unwrap();
+        let lines: Vec<&str> = gcode.lines().collect();
+        // First approach comes down from above; the next slows down 2 mm short.
+        assert_eq!(lines[2..4], ["G0 X0 Y0 Z3 F6000", "G1 Z1 F1200"]);
+        assert_eq!(lines[8..10], ["G0 X18 F6000", "G1 X20 F1200"]);
+    }
+
+    #[test]
+    fn unchanged_axes_and_no_op_moves_are_omitted() {
+        let layer = vec![
+            path(&[(0.0, 0.0, 1.0), (10.0, 0.0, 1.0), (10.0, 0.0, 1.0), (10.0, 10.0, 1.0)], false),
+            path(&[(10.0, 10.0, 1.0), (0.0, 10.0, 1.0)], false),
+        ];
+        let gcode = to_gcode(&set(layer), &GcodeConfig::default()).unwrap();
+        assert_eq!(gcode.lines().filter(|l| l.contains('Z')).count(), 1);
+        assert_eq!(
+            gcode.lines().skip(2).collect::<Vec<_>>(),
+            vec!["G0 X0 Y0 Z1 F6000", "G1 X10 F1200", "G1 Y10", "G1 X0"]
+        );
+    }
+
+    #[test]
+    fn metadata_header_records_settings() {
+        let cfg = GcodeConfig {
+            metadata_header: true,
+            generator: Some("additive".to_string()),
+            layer_height: Some(0.2),
+            tool: Some("0.4 mm nozzle".to_string()),
+            jerk: Some(8.0),
+            ..Default::default()
+        };
+        let gcode = to_gcode(&set(vec![square(0.2)]), &cfg).unwrap();
+        let header: Vec<&str> = gcode.lines().take_while(|l| l.starts_with(';')).collect();
+        assert_eq!(header[0], format!("; ironpath {}", env!("CARGO_PKG_VERSION")));
+        assert!(header[1].starts_with("; generated_at: "));
+        assert_eq!(
+            header[2..],
+            [
+                "; generator: additive",
+                "; layer_height: 0.2",
+                "; tool: 0.4 mm nozzle",
+                "; feed_rate: 1200",
+                "; travel_rate: 6000",
+                "; jerk: 8",
+            ]
+        );
+        let plain = to_gcode(&set(vec![square(0.2)]), &GcodeConfig::default()).unwrap();
+        assert!(plain.starts_with("G21"));
+    }
+
+    #[test]
+    fn kind_prologue_runs_once_per_run_of_bridges() {
+        let bridge = |y: Real| ToolpathSegment { kind: SegmentKind::Bridge, ..path(&[(0.0, y, 1.0), (5.0, y, 1.0)], false) };
+        let cfg = GcodeConfig {
+            kind_prologue: HashMap::from([(SegmentKind::Bridge, "M900 K0\nM106 S255\n".to_string())]),
+            ..Default::default()
+        };
+        let segments = set(vec![square(1.0), bridge(20.0), bridge(21.0), square(1.0), bridge(22.0)]);
+        let gcode = to_gcode(&segments, &cfg).unwrap();
+        let lines: Vec<&str> = gcode.lines().collect();
+        let starts: Vec<usize> = lines.iter().enumerate().filter(|(_, l)| **l == "M900 K0").map(|(i, _)| i).collect();
+        assert_eq!(starts.len(), 2);
+        for i in starts {
+            assert_eq!(lines[i + 1], "M106 S255");
+            assert!(lines[i + 2].starts_with("G0 ") && lines[i + 2].contains("Y2"));
+        }
+    }
+
+    #[test]
+    fn extra_prime_goes_on_the_first_move_after_a_travel() {
+        let segments = set(vec![square(0.2), square_at(20.0, 0.0, 10.0, 0.2)]);
+        let extrusions = |extra_prime_volume: Real| {
+            let cfg = GcodeConfig {
+                extrusion: Some(ExtrusionConfig { extra_prime_volume, ..Default::default() }),
+                ..Default::default()
+            };
+            let gcode = to_gcode(&segments, &cfg).unwrap();
+            gcode
+                .lines()
+                .filter(|l| l.starts_with("G1 X") || l.starts_with("G1 Y"))
+                .map(|l| l.split(' ').find_map(|word| word.strip_prefix('E')).unwrap().parse::<Real>().unwrap())
+                .collect::<Vec<_>>()
+        };
+        let plain = extrusions(0.0);
+        let primed = extrusions(1.0);
+        let extra = 1.0 / ExtrusionConfig::default().filament_area();
+        let differences: Vec<Real> = primed.iter().zip(&plain).map(|(a, b)| a - b).collect();
+        // Only the first move of the second square, after the retracting travel.
+        assert!((differences[4] - extra).abs() < 1e-4);
+        assert!(differences.iter().enumerate().all(|(i, d)| i == 4 || d.abs() < 1e-9));
+    }
+
+    #[test]
+    fn corner_smoothing_slows_either_side_of_a_right_angle() {
+        let cfg = GcodeConfig { corner_smoothing: Some(MotionLimits::default()), ..Default::default() };
+        let corner = path(&[(0.0, 0.0, 1.0), (20.0, 0.0, 1.0), (20.0, 20.0, 1.0)], false);
+        let gcode = to_gcode(&set(vec![corner]), &cfg).unwrap();
+        // 20 mm/s cruise, jerk 10 across a sqrt(2) direction change: 7.07 mm/s at the
+        // corner, reached over (20² - 7.07²) / (2 * 1000) = 0.175 mm on each side.
+        assert!(gcode.ends_with("G1 X19.825 F1200\nG1 X20 F424.264 ; corner\nG1 Y0.175 ; corner\nG1 Y20 F1200\n"));
+    }
+
+    #[test]
+    fn rapid_as_g1_emits_travels_as_feed_moves() {
+        let segments = set(vec![square(1.0), square_at(20.0, 0.0, 10.0, 1.0)]);
+        let travels = |cfg: &GcodeConfig| {
+            let gcode = to_gcode(&segments, cfg).unwrap();
+            gcode.lines().filter(|l| l.ends_with("F6000")).map(str::to_string).collect::<Vec<_>>()
+        };
+        assert_eq!(travels(&GcodeConfig::default()), vec!["G0 X0 Y0 Z1 F6000", "G0 X20 F6000"]);
+        let cfg = GcodeConfig { rapid_as_g1: true, ..Default::default() };
+        assert_eq!(travels(&cfg), vec!["G1 X0 Y0 Z1 F6000", "G1 X20 F6000"]);
+        assert!(!to_gcode(&segments, &cfg).unwrap().contains("G0"));
+    }
+
+    #[test]
+    fn validate_gcode_flags_unsupported_codes() {
+        let text = "G21\nG90\nG5 X1 Y2 ; spline\nG01 X3 (comment G6)\nM104 S200";
+        let unsupported = validate_gcode(text, &allowed(&["G1", "G21", "G90"]));
+        assert_eq!(
+            unsupported,
+            vec![
+                UnsupportedCode { line: 3, code: "G5".to_string() },
+                UnsupportedCode { line: 5, code: "M104".to_string() },
+            ]
+        );
+    }
+
+    #[test]
+    fn validate_gcode_survives_odd_numbers() {
+        let unsupported = validate_gcode("G.5 X1\nG.\nG00.0", &allowed(&["G0.0"]));
+        let codes: Vec<&str> = unsupported.iter().map(|u| u.code.as_str()).collect();
+        assert_eq!(codes, vec!["G.5", "G."]);
+    }
+}
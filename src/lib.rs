@@ -2,20 +2,441 @@
 #![allow(unused_imports)]
 #![forbid(unsafe_code)]
 
+use std::collections::HashMap;
+
 use csgrs::float_types::{PI, Real};
-use nalgebra::{Point3, Vector3};
+use nalgebra::{Matrix4, Point2, Point3, Vector2, Vector3};
 use csgrs::polygon::Polygon;
 use csgrs::vertex::Vertex;
 use csgrs::plane::Plane;
 
+mod contour;
+pub mod gcode;
+pub mod motion;
+pub use gcode::{to_gcode, validate_gcode, ExtrusionConfig, GcodeConfig, ParkConfig, UnsupportedCode};
+pub use motion::{check_ramp_angle, estimate_time_accel, MotionLimits, MoveMerging, RampWarning, SimParams, SimResult};
+
+#[allow(clippy::upper_case_acronyms)]
 type CSG = csgrs::csg::CSG<()>;
 
+/// Tolerance used when deciding whether two segments sit on the same layer.
+const LAYER_EPSILON: Real = 1e-7;
+
+/// XY tolerance for treating contour points as touching.
+const CONTOUR_EPSILON: Real = 1e-6;
+
+/// How many layers a preview merges into one slice.
+const PREVIEW_LAYER_MULTIPLIER: Real = 4.0;
+
+/// Errors raised while turning toolpaths into machine output.
+#[derive(Debug, Clone, PartialEq)]
+pub enum ToolpathError {
+    /// A point falls outside the machine's `[0, bed_size]` work volume.
+    OutOfBounds {
+        point: Point3<Real>,
+        bed_size: Vector3<Real>,
+    },
+    /// The model rises above the configured `max_z`, so its top would be lost.
+    ModelTooTall {
+        model_max_z: Real,
+        max_z: Real,
+    },
+}
+
+impl std::fmt::Display for ToolpathError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ToolpathError::OutOfBounds { point, bed_size } => write!(
+                f,
+                "point ({}, {}, {}) lies outside the bed (0..{}, 0..{}, 0..{})",
+                point.x, point.y, point.z, bed_size.x, bed_size.y, bed_size.z
+            ),
+            ToolpathError::ModelTooTall { model_max_z, max_z } => write!(
+                f,
+                "model top at z={} is above max_z={}; {} would be clipped",
+                model_max_z,
+                max_z,
+                model_max_z - max_z
+            ),
+        }
+    }
+}
+
+impl std::error::Error for ToolpathError {}
+
+/// What a segment is used for, so later passes can order or tune it per feature.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Default)]
+pub enum SegmentKind {
+    /// An outer or inner wall of the cross-section.
+    #[default]
+    Perimeter,
+    /// Solid (100% density) fill anchored to the perimeters.
+    SolidInfill,
+    /// Material spanning open space between two supported regions.
+    Bridge,
+    /// Sparse interior fill.
+    Infill,
+    /// Purge/wipe tower used to flush material after a tool change.
+    PurgeTower,
+}
+
 /// A simplified structure representing a toolpath as polylines in 3D.
 /// In more advanced designs, you might store feed rates, speeds, 
 /// tool orientation, or arcs, etc.
 #[derive(Debug, Clone)]
 pub struct ToolpathSegment {
     pub points: Vec<Point3<Real>>,
+    pub kind: SegmentKind,
+    /// Whether the path is a loop that returns to its first point.
+    pub closed: bool,
+    /// Tool (extruder or cutter) index the segment is made with.
+    pub tool: usize,
+    /// Z of the slice plane the segment came from, kept even when the points are
+    /// later moved off that plane (transforms, bed compensation, helices).
+    pub layer_z: Option<Real>,
+}
+
+impl ToolpathSegment {
+    /// Where the tool ends up after following the path.
+    pub fn end_point(&self) -> Option<Point3<Real>> {
+        if self.closed {
+            self.points.first().copied()
+        } else {
+            self.points.last().copied()
+        }
+    }
+
+    /// Z height of the segment, taken from its first point.
+    pub fn z(&self) -> Option<Real> {
+        self.points.first().map(|p| p.z)
+    }
+
+    /// Layer the segment belongs to: its slice Z when known, otherwise `z()`.
+    pub fn layer(&self) -> Option<Real> {
+        self.layer_z.or_else(|| self.z())
+    }
+
+    /// Consecutive point pairs of the path, including the closing edge back to
+    /// the first point for closed loops that don't already repeat it.
+    pub fn edges(&self) -> impl Iterator<Item = (Point3<Real>, Point3<Real>)> + '_ {
+        let closing = match (self.points.first(), self.points.last()) {
+            (Some(first), Some(last)) if self.closed && self.points.len() > 2 && first != last => Some((*last, *first)),
+            _ => None,
+        };
+        self.points.windows(2).map(|w| (w[0], w[1])).chain(closing)
+    }
+
+    /// Total path length in model units.
+    pub fn length(&self) -> Real {
+        self.edges().map(|(a, b)| (b - a).norm()).sum()
+    }
+
+    /// Drop points that deviate less than `epsilon` from the simplified path
+    /// (Ramer-Douglas-Peucker). Closed loops stay closed and keep at least three points.
+    pub fn simplify(&mut self, epsilon: Real) {
+        let mut path = contour::loop_vertices(self).to_vec();
+        let repeats_start = path.len() < self.points.len();
+        let is_loop = self.closed && path.len() >= 3;
+        if is_loop {
+            path.push(path[0]);
+        }
+        let mut simplified = contour::douglas_peucker(&path, epsilon);
+        if is_loop {
+            if simplified.len() < 4 {
+                return;
+            }
+            if !repeats_start {
+                simplified.pop();
+            }
+        }
+        self.points = simplified;
+    }
+
+    /// Drop points lying within `eps` of the point before them, so no zero-length
+    /// moves reach the writer. Every other point is kept as-is, so curved runs of
+    /// short but real edges are untouched.
+    pub fn remove_zero_length_moves(&mut self, eps: Real) {
+        self.points.dedup_by(|p, prev| (*p - *prev).norm() <= eps);
+    }
+
+    /// Extend both free ends of an open path by `length` along its first and last
+    /// edges, so the tool leads on to the profile and off it instead of starting
+    /// and stopping on it. Closed loops are left alone.
+    pub fn add_leads(&mut self, length: Real) {
+        if self.closed || length <= 0.0 || self.points.len() < 2 {
+            return;
+        }
+        // Lead off `end` away from the nearest following point that isn't on top of it.
+        fn lead<'a>(end: Point3<Real>, mut rest: impl Iterator<Item = &'a Point3<Real>>, length: Real) -> Option<Point3<Real>> {
+            let from = rest.find(|p| (end - *p).norm() > Real::EPSILON)?;
+            let d = end - from;
+            Some(end + d * (length / d.norm()))
+        }
+        let start = lead(self.points[0], self.points.iter().skip(1), length);
+        let end = lead(self.points[self.points.len() - 1], self.points.iter().rev().skip(1), length);
+        if let Some(start) = start {
+            self.points.insert(0, start);
+        }
+        if let Some(end) = end {
+            self.points.push(end);
+        }
+    }
+
+    /// Signed area enclosed by the path projected onto XY, treating it as a closed
+    /// loop. Counter-clockwise loops are positive, clockwise loops negative.
+    pub fn area_xy_closed(&self) -> Real {
+        // Shoelace formula over every edge plus the closing edge, whether or not
+        // the path is flagged `closed` (a repeated start point adds nothing).
+        let (Some(first), Some(last)) = (self.points.first(), self.points.last()) else {
+            return 0.0;
+        };
+        let closing = std::iter::once((*last, *first));
+        0.5 * self
+            .points
+            .windows(2)
+            .map(|w| (w[0], w[1]))
+            .chain(closing)
+            .map(|(a, b)| a.x * b.y - b.x * a.y)
+            .sum::<Real>()
+    }
+}
+
+/// Net XY area enclosed by a set of contours: CCW loops add solid area and CW
+/// loops (holes) subtract it, so an annulus yields outer minus inner area. Open
+/// paths (purge towers, profiles) enclose nothing and are skipped.
+pub fn net_area_xy(segments: &[ToolpathSegment]) -> Real {
+    segments.iter().filter(|seg| seg.closed).map(|seg| seg.area_xy_closed()).sum()
+}
+
+/// Largest distance from a vertex of `original` to the path of `simplified`
+/// (closing edge included for closed loops), e.g. to confirm a `simplify` pass
+/// stayed within its tolerance. Returns `0.0` when `original` is empty and
+/// infinity when only `simplified` is.
+pub fn max_deviation(original: &ToolpathSegment, simplified: &ToolpathSegment) -> Real {
+    let edges: Vec<(Point3<Real>, Point3<Real>)> = match simplified.points.as_slice() {
+        [only] => vec![(*only, *only)],
+        _ => simplified.edges().collect(),
+    };
+    original
+        .points
+        .iter()
+        .map(|p| {
+            edges
+                .iter()
+                .map(|(a, b)| contour::distance_to_segment(p, a, b))
+                .fold(Real::INFINITY, Real::min)
+        })
+        .fold(0.0, Real::max)
+}
+
+/// Area each loop contributes to the material of its layer, decided by nesting
+/// rather than winding: boundaries count positive, holes negative.
+fn material_areas(loops: &[ToolpathSegment]) -> Vec<Real> {
+    contour::nesting_depths(loops, CONTOUR_EPSILON)
+        .into_iter()
+        .zip(loops)
+        .map(|(depth, seg)| if depth % 2 == 0 { seg.area_xy_closed().abs() } else { -seg.area_xy_closed().abs() })
+        .collect()
+}
+
+/// First-layer contact with the build plate, compared with where the model's
+/// weight sits.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct AdhesionReport {
+    /// Material area of the lowest layer.
+    pub footprint_area: Real,
+    /// Area centroid of that layer, if it has any area.
+    pub footprint_centroid: Option<Point2<Real>>,
+    /// Model center of mass (uniform density).
+    pub center_of_mass: Point3<Real>,
+    /// Whether the center of mass, projected straight down, lands on the
+    /// footprint. `false` means the part is likely to tip over or peel off.
+    pub com_over_footprint: bool,
+    /// Height of the center of mass above the first layer divided by the
+    /// footprint's effective width (`sqrt(footprint_area)`). Tall, narrow-based
+    /// parts score high even when perfectly balanced.
+    pub slenderness: Real,
+    /// `com_over_footprint`, and `slenderness` at most the limit given to
+    /// `adhesion_report`.
+    pub stable: bool,
+}
+
+/// Measure the first-layer footprint of `set` (its lowest layer's closed
+/// contours) and check that the center of mass of `model` sits above it and
+/// no higher than `max_slenderness` times the footprint's width. How slender a
+/// part can stand depends on the material, bed and print speed, so there is no
+/// one right limit; pass `Real::INFINITY` to check the balance alone.
+pub fn adhesion_report(set: &ToolpathSet, model: &CSG, max_slenderness: Real) -> AdhesionReport {
+    let lowest = set
+        .layer_ranges()
+        .into_iter()
+        .filter_map(|range| Some((set.segments[range.start].layer()?, range)))
+        .min_by(|a, b| a.0.total_cmp(&b.0));
+    let base_z = lowest.as_ref().map_or(0.0, |(z, _)| *z);
+    let loops: Vec<ToolpathSegment> = lowest
+        .map(|(_, range)| set.segments[range].iter().filter(|seg| seg.closed).cloned().collect())
+        .unwrap_or_default();
+
+    let areas = material_areas(&loops);
+    let footprint_area: Real = areas.iter().sum();
+    let footprint_centroid = (footprint_area.abs() > Real::EPSILON).then(|| {
+        let (mut x, mut y) = (0.0, 0.0);
+        for (seg, area) in loops.iter().zip(&areas) {
+            if let Some((cx, cy)) = contour::centroid_xy(seg) {
+                x += cx * area;
+                y += cy * area;
+            }
+        }
+        Point2::new(x / footprint_area, y / footprint_area)
+    });
+
+    let (_, center_of_mass, _) = model.mass_properties(1.0);
+    // Inside material when an odd number of loops enclose the point.
+    let enclosing = loops.iter().filter(|seg| contour::point_in_loop(&center_of_mass, seg)).count();
+    let com_over_footprint = enclosing % 2 == 1;
+    let slenderness = if footprint_area > Real::EPSILON {
+        (center_of_mass.z - base_z).max(0.0) / footprint_area.sqrt()
+    } else {
+        Real::INFINITY
+    };
+    AdhesionReport {
+        footprint_area,
+        footprint_centroid,
+        center_of_mass,
+        com_over_footprint,
+        slenderness,
+        stable: com_over_footprint && slenderness <= max_slenderness,
+    }
+}
+
+/// Convex deficiency of a layer: the XY area of its contours' convex hull minus
+/// the material area, i.e. the notches and bays a support planner may need to
+/// bridge. Material area is taken from nesting (boundaries add, holes subtract),
+/// so it doesn't depend on winding. Only closed loops count.
+pub fn layer_concavity(segments: &[ToolpathSegment]) -> Real {
+    let loops: Vec<ToolpathSegment> = segments.iter().filter(|seg| seg.closed).cloned().collect();
+    let material: Real = material_areas(&loops).into_iter().sum();
+    let hull = contour::convex_hull_area_xy(loops.iter().flat_map(|seg| &seg.points));
+    (hull - material).max(0.0)
+}
+
+/// Convert one cross-section polygon (planar, in XY) into a perimeter segment at
+/// height `z`. Open polygons (e.g. an edge profile) become open segments that
+/// are never joined end to start. Coincident consecutive vertices are merged,
+/// and closed polygons left with fewer than 3 vertices (open ones with fewer
+/// than 2) yield `None`.
+pub fn polygon_to_segment(poly: &Polygon<()>, z: Real) -> Option<ToolpathSegment> {
+    let min_points = if poly.open { 2 } else { 3 };
+    if poly.vertices.len() < min_points {
+        return None;
+    }
+    // Convert the polygon to a 2D polyline, then that polyline to a 3D path at z.
+    let pline2d = poly.to_polyline();
+    let points = pline2d
+        .vertex_data
+        .iter()
+        .map(|v2d| Point3::new(v2d.x, v2d.y, z))
+        .collect();
+    let mut segment = ToolpathSegment {
+        points,
+        kind: SegmentKind::Perimeter,
+        closed: !poly.open,
+        tool: 0,
+        layer_z: Some(z),
+    };
+    segment.remove_zero_length_moves(CONTOUR_EPSILON);
+    (segment.points.len() >= min_points).then_some(segment)
+}
+
+/// Lowest Z that clears the whole model by `margin`: its top plus the margin.
+/// Useful as a default rapid/clearance height for subtractive jobs.
+pub fn safe_rapid_z(model: &CSG, margin: Real) -> Real {
+    model.bounding_box().maxs.z + margin
+}
+
+/// Material removal rate over a subtractive job, in units³/min.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct MrrReport {
+    /// Total volume removed divided by total cutting time.
+    pub average: Real,
+    /// Highest rate of any single level.
+    pub peak: Real,
+}
+
+/// Estimate the material removal rate of `set` cut as full-width slots: each
+/// level removes `tool_diameter` × its depth for every unit travelled at `feed`
+/// (units/min). A level's depth is its Z distance from the level before it, and
+/// `step_down` for the first level and for segments without a layer. Peaks well
+/// above the average flag levels that may overload the tool.
+pub fn mrr_profile(set: &ToolpathSet, tool_diameter: Real, step_down: Real, feed: Real) -> MrrReport {
+    let mut volume = 0.0;
+    let mut length = 0.0;
+    let mut peak: Real = 0.0;
+    let mut previous: Option<Real> = None;
+    for range in set.layer_ranges() {
+        let layer = set.segments[range.start].layer();
+        let depth = match (previous, layer) {
+            (Some(a), Some(b)) => (a - b).abs(),
+            _ => step_down,
+        };
+        previous = layer;
+        let level_length: Real = set.segments[range].iter().map(|seg| seg.length()).sum();
+        if level_length <= 0.0 {
+            continue;
+        }
+        volume += tool_diameter * depth * level_length;
+        length += level_length;
+        peak = peak.max(tool_diameter * depth * feed);
+    }
+    let average = if length > 0.0 { volume / (length / feed) } else { 0.0 };
+    MrrReport { average, peak }
+}
+
+/// How `check_model_height` treats a model taller than `max_z`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum HeightPolicy {
+    /// Report the clip and let generation go ahead.
+    #[default]
+    Warn,
+    /// Refuse with `ToolpathError::ModelTooTall`.
+    Error,
+}
+
+/// How much of a model lies above the configured `max_z`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct HeightClip {
+    pub model_max_z: Real,
+    pub max_z: Real,
+    /// Height of the part that no layer will reach.
+    pub clipped: Real,
+}
+
+/// Compare the model's top against `max_z`. Slicing stops at `max_z`, so anything
+/// above it silently goes missing; this reports it as a `HeightClip` or, under
+/// `HeightPolicy::Error`, fails instead. `Ok(None)` means the model fits.
+pub fn check_model_height(model: &CSG, max_z: Real, policy: HeightPolicy) -> Result<Option<HeightClip>, ToolpathError> {
+    let model_max_z = model.bounding_box().maxs.z;
+    if model_max_z <= max_z + LAYER_EPSILON {
+        return Ok(None);
+    }
+    match policy {
+        HeightPolicy::Warn => Ok(Some(HeightClip { model_max_z, max_z, clipped: model_max_z - max_z })),
+        HeightPolicy::Error => Err(ToolpathError::ModelTooTall { model_max_z, max_z }),
+    }
+}
+
+/// Dump every point as CSV with columns `layer,segment,index,x,y,z`, where `layer`
+/// counts layers in emission order and `segment` counts segments within a layer.
+pub fn export_layers_csv(set: &ToolpathSet) -> String {
+    let mut csv = String::from("layer,segment,index,x,y,z\n");
+    for (layer, range) in set.layer_ranges().into_iter().enumerate() {
+        for (segment, seg) in set.segments[range].iter().enumerate() {
+            for (index, p) in seg.points.iter().enumerate() {
+                csv.push_str(&format!("{},{},{},{},{},{}\n", layer, segment, index, p.x, p.y, p.z));
+            }
+        }
+    }
+    csv
 }
 
 /// A collection of toolpaths (e.g. for each layer in additive, or each pass in subtractive).
@@ -24,6 +445,486 @@ pub struct ToolpathSet {
     pub segments: Vec<ToolpathSegment>,
 }
 
+impl ToolpathSet {
+    /// Index ranges of consecutive segments on the same layer, in emission order.
+    fn layer_ranges(&self) -> Vec<std::ops::Range<usize>> {
+        let mut ranges = Vec::new();
+        let mut start = 0;
+        for i in 1..=self.segments.len() {
+            let same_layer = i < self.segments.len()
+                && match (self.segments[start].layer(), self.segments[i].layer()) {
+                    (Some(a), Some(b)) => (a - b).abs() <= LAYER_EPSILON,
+                    _ => false,
+                };
+            if !same_layer {
+                ranges.push(start..i);
+                start = i;
+            }
+        }
+        ranges
+    }
+
+    /// Total path length of each segment kind across the whole set.
+    pub fn length_by_kind(&self) -> HashMap<SegmentKind, Real> {
+        let mut lengths = HashMap::new();
+        for seg in &self.segments {
+            *lengths.entry(seg.kind).or_insert(0.0) += seg.length();
+        }
+        lengths
+    }
+
+    /// Path length of each segment kind per layer, as `(z, lengths)` in layer order.
+    pub fn layer_length_by_kind(&self) -> Vec<(Real, HashMap<SegmentKind, Real>)> {
+        self.layer_ranges()
+            .into_iter()
+            .filter_map(|range| {
+                let z = self.segments[range.start].layer()?;
+                let mut lengths = HashMap::new();
+                for seg in &self.segments[range] {
+                    *lengths.entry(seg.kind).or_insert(0.0) += seg.length();
+                }
+                Some((z, lengths))
+            })
+            .collect()
+    }
+
+    /// Work out the feed scale and fan speed for every layer so none prints faster
+    /// than `cfg.min_layer_time`. `feed_rate` is the nominal feed in units/min.
+    /// `to_gcode` applies the same plan when `GcodeConfig::cooling` is set.
+    pub fn layer_cooling(&self, feed_rate: Real, cfg: &CoolingConfig) -> Vec<LayerCooling> {
+        self.layer_ranges()
+            .into_iter()
+            .filter_map(|range| {
+                let z = self.segments[range.start].layer()?;
+                let length: Real = self.segments[range].iter().map(|seg| seg.length()).sum();
+                let layer_time = length / feed_rate * 60.0;
+                Some(cfg.cooling_for(z, layer_time))
+            })
+            .collect()
+    }
+
+    /// Reorder segments within each layer to balance tool changes against travel.
+    /// Segments are picked greedily by `travel * weights.travel` plus
+    /// `weights.tool_change` when the pick needs a different tool, so a high
+    /// tool-change weight groups same-tool segments even at some travel cost.
+    /// Layers themselves are never reordered.
+    pub fn optimize(&mut self, weights: OptimizeWeights) {
+        let mut ordered = Vec::with_capacity(self.segments.len());
+        let mut position: Option<Point3<Real>> = None;
+        let mut tool: Option<usize> = None;
+
+        for range in self.layer_ranges() {
+            let mut remaining: Vec<ToolpathSegment> = self.segments[range].to_vec();
+            while !remaining.is_empty() {
+                let cost = |seg: &ToolpathSegment| {
+                    let travel = match (position, seg.points.first()) {
+                        (Some(from), Some(to)) => (to - from).norm(),
+                        _ => 0.0,
+                    };
+                    let change = if tool.is_some_and(|t| t != seg.tool) { 1.0 } else { 0.0 };
+                    travel * weights.travel + change * weights.tool_change
+                };
+                let best = (0..remaining.len())
+                    .min_by(|&a, &b| cost(&remaining[a]).total_cmp(&cost(&remaining[b])))
+                    .unwrap_or(0);
+                let seg = remaining.remove(best);
+                position = seg.end_point().or(position);
+                tool = Some(seg.tool);
+                ordered.push(seg);
+            }
+        }
+        self.segments = ordered;
+    }
+
+    /// Add a purge tower pass after every tool change, on each layer where one
+    /// happens. Each pass extrudes `cfg.purge_volume` as a serpentine over the
+    /// tower footprint, continuing where the previous pass of the layer stopped.
+    pub fn add_purge_tower(&mut self, cfg: &PurgeTowerConfig) {
+        let length_per_change = cfg.purge_volume / (cfg.line_width * cfg.layer_height);
+        let mut result = Vec::with_capacity(self.segments.len());
+        let mut tool: Option<usize> = None;
+
+        for range in self.layer_ranges() {
+            let mut purged = 0.0;
+            for seg in &self.segments[range] {
+                if tool.is_some_and(|t| t != seg.tool) {
+                    if let Some(z) = seg.z() {
+                        let points = cfg.serpentine(purged, purged + length_per_change, z);
+                        result.push(ToolpathSegment {
+                            points,
+                            kind: SegmentKind::PurgeTower,
+                            closed: false,
+                            tool: seg.tool,
+                            layer_z: seg.layer_z,
+                        });
+                        purged += length_per_change;
+                    }
+                }
+                tool = Some(seg.tool);
+                result.push(seg.clone());
+            }
+        }
+        self.segments = result;
+    }
+
+    /// Clip every segment to the XY region enclosed by `mask` (a closed polygon;
+    /// its Z values are ignored). Paths are cut where they cross the mask and
+    /// only the inside runs are kept, as open segments; no edges are added along
+    /// the mask. Segments entirely outside are dropped.
+    pub fn clip_to_region(&mut self, mask: &[Point3<Real>]) {
+        if mask.len() < 3 {
+            return;
+        }
+        self.segments = self
+            .segments
+            .iter()
+            .flat_map(|seg| contour::clip_to_mask(seg, mask))
+            .collect();
+    }
+
+    /// Apply an affine transform to every point, e.g. to map model space onto the
+    /// machine's fixture coordinates.
+    pub fn transform(&mut self, matrix: &Matrix4<Real>) {
+        for seg in &mut self.segments {
+            for p in &mut seg.points {
+                *p = matrix.transform_point(p);
+            }
+        }
+    }
+
+    /// Run the toolpath on a simulated machine and report where it ends up, how
+    /// much it extruded and how long it took. Handy for catching configuration
+    /// mistakes before anything reaches hardware.
+    pub fn simulate(&self, params: &SimParams) -> SimResult {
+        motion::simulate(self, params)
+    }
+
+    /// Raise or lower the bottom `mesh.fade_layers` layers to follow `mesh`,
+    /// scaling the offset down linearly with each layer. Points move but keep
+    /// their `layer_z`, so layers still group as before. Only existing points are
+    /// adjusted; long edges are not subdivided to follow the mesh between them.
+    pub fn apply_bed_mesh(&mut self, mesh: &BedMesh) {
+        let fade = mesh.fade_layers as Real;
+        for (index, range) in self.layer_ranges().into_iter().enumerate().take(mesh.fade_layers) {
+            let scale = 1.0 - index as Real / fade;
+            for p in self.segments[range].iter_mut().flat_map(|seg| seg.points.iter_mut()) {
+                p.z += mesh.offset_at(p.x, p.y) * scale;
+            }
+        }
+    }
+
+    /// Copy every segment whose layer lies within `eps` of `source_z` to
+    /// `target_z`, shifting its points by the Z difference. The copies join the
+    /// end of the target layer if one already exists, otherwise the end of the set.
+    pub fn clone_layer(&mut self, source_z: Real, target_z: Real, eps: Real) {
+        let near = |seg: &ToolpathSegment, z: Real| seg.layer().is_some_and(|layer| (layer - z).abs() <= eps);
+        let shift = Vector3::new(0.0, 0.0, target_z - source_z);
+        let copies: Vec<ToolpathSegment> = self
+            .segments
+            .iter()
+            .filter(|seg| near(seg, source_z))
+            .map(|seg| ToolpathSegment {
+                points: seg.points.iter().map(|p| p + shift).collect(),
+                layer_z: Some(target_z),
+                ..seg.clone()
+            })
+            .collect();
+        let at = self
+            .segments
+            .iter()
+            .rposition(|seg| near(seg, target_z))
+            .map_or(self.segments.len(), |i| i + 1);
+        self.segments.splice(at..at, copies);
+    }
+
+    /// Regroup each layer's closed contours into polygons with holes: every
+    /// boundary (even nesting depth) collects the holes directly inside it, and
+    /// islands inside holes become polygons of their own. Boundaries come out
+    /// CCW and holes CW; open paths are left out. `eps` is the touching tolerance
+    /// used for nesting.
+    pub fn to_layer_polygons(&self, eps: Real) -> Vec<LayerPolygons> {
+        let oriented = |seg: &ToolpathSegment, ccw: bool| {
+            let mut seg = seg.clone();
+            if (seg.area_xy_closed() > 0.0) != ccw {
+                seg.points.reverse();
+            }
+            seg
+        };
+        self.layer_ranges()
+            .into_iter()
+            .map(|range| {
+                let z = self.segments[range.start].layer();
+                let loops: Vec<ToolpathSegment> = self.segments[range]
+                    .iter()
+                    .filter(|seg| seg.closed && contour::loop_vertices(seg).len() >= 3)
+                    .cloned()
+                    .collect();
+                let depths = contour::nesting_depths(&loops, eps);
+                let mut polygons: Vec<PolygonWithHoles> = Vec::new();
+                let is_hole = |i: usize| depths[i] % 2 == 1;
+                let mut index_of = vec![None; loops.len()];
+                for (i, seg) in loops.iter().enumerate() {
+                    if !is_hole(i) {
+                        index_of[i] = Some(polygons.len());
+                        polygons.push(PolygonWithHoles { boundary: oriented(seg, true), holes: Vec::new() });
+                    }
+                }
+                for (i, seg) in loops.iter().enumerate() {
+                    if !is_hole(i) {
+                        continue;
+                    }
+                    // The hole belongs to the boundary exactly one level out.
+                    let parent = (0..loops.len())
+                        .find(|&j| depths[j] + 1 == depths[i] && contour::loop_contains(&loops[j], seg, eps))
+                        .and_then(|j| index_of[j]);
+                    if let Some(p) = parent {
+                        polygons[p].holes.push(oriented(seg, false));
+                    }
+                }
+                LayerPolygons { z, polygons }
+            })
+            .collect()
+    }
+
+    /// Make every closed contour follow the convention of its layer: boundaries
+    /// counter-clockwise, holes clockwise (decided by nesting, not by the current
+    /// winding). Wrongly wound loops are reversed and reported.
+    pub fn check_and_fix_orientation(&mut self) -> Vec<OrientationFix> {
+        let mut fixes = Vec::new();
+        for range in self.layer_ranges() {
+            let offset = range.start;
+            let layer = &mut self.segments[range];
+            let depths = contour::nesting_depths(layer, CONTOUR_EPSILON);
+            for (i, (seg, depth)) in layer.iter_mut().zip(depths).enumerate() {
+                if !seg.closed || contour::loop_vertices(seg).len() < 3 {
+                    continue;
+                }
+                let is_hole = depth % 2 == 1;
+                if (seg.area_xy_closed() > 0.0) == is_hole {
+                    seg.points.reverse();
+                    fixes.push(OrientationFix {
+                        segment: offset + i,
+                        layer: seg.layer(),
+                        is_hole,
+                    });
+                }
+            }
+        }
+        fixes
+    }
+
+    /// Merge contours that share boundary edges within a layer, so the interface
+    /// between two touching bodies isn't walled twice, whether it comes back as
+    /// two loops or as one loop running along it both ways. Contours on different tools
+    /// or of different kinds are never merged, and merged loops keep their own
+    /// tool and kind. Layers without coincident edges are left untouched.
+    pub fn weld_coincident_contours(&mut self, eps: Real) {
+        let mut welded = Vec::with_capacity(self.segments.len());
+        for range in self.layer_ranges() {
+            let layer = &self.segments[range];
+            match contour::weld_layer(layer, eps) {
+                Some(merged) => welded.extend(merged),
+                None => welded.extend_from_slice(layer),
+            }
+        }
+        self.segments = welded;
+    }
+
+    /// Within each layer, gather all infill into one run where the first infill
+    /// segment was: sparse infill first, then all solid infill together, so the
+    /// machine doesn't switch back and forth between them. Order within each
+    /// kind, and among the other segments, is preserved.
+    pub fn order_sparse_before_solid(&mut self) {
+        let is_infill = |seg: &ToolpathSegment| matches!(seg.kind, SegmentKind::Infill | SegmentKind::SolidInfill);
+        for range in self.layer_ranges() {
+            let layer = &mut self.segments[range];
+            let Some(first) = layer.iter().position(is_infill) else {
+                continue;
+            };
+            let (mut infill, others): (Vec<ToolpathSegment>, Vec<ToolpathSegment>) =
+                layer.iter().cloned().partition(|seg| is_infill(seg));
+            infill.sort_by_key(|seg| seg.kind == SegmentKind::SolidInfill);
+            let reordered = others[..first].iter().chain(&infill).chain(&others[first..]);
+            for (slot, seg) in layer.iter_mut().zip(reordered) {
+                *slot = seg.clone();
+            }
+        }
+    }
+
+    /// Reorder each layer so bridges are printed after the perimeters and solid
+    /// fill they anchor to. The relative order within each group is preserved.
+    pub fn order_bridges_last(&mut self) {
+        for range in self.layer_ranges() {
+            self.segments[range].sort_by_key(|seg| seg.kind == SegmentKind::Bridge);
+        }
+    }
+}
+
+/// Purge tower for multi-material prints.
+#[derive(Debug, Clone)]
+pub struct PurgeTowerConfig {
+    /// Minimum XY corner of the tower.
+    pub position: Point2<Real>,
+    /// Tower size along X and Y.
+    pub footprint: Vector2<Real>,
+    /// Volume (mm³) to purge for every tool change.
+    pub purge_volume: Real,
+    /// Extrusion width of the tower lines.
+    pub line_width: Real,
+    /// Layer height the tower is printed at.
+    pub layer_height: Real,
+}
+
+impl PurgeTowerConfig {
+    /// Points of the serpentine between path lengths `from` and `to` at height `z`.
+    /// Rows run along X and step along Y; once the footprint is covered the
+    /// path turns round and retraces itself, so any length fits.
+    fn serpentine(&self, from: Real, to: Real, z: Real) -> Vec<Point3<Real>> {
+        let rows = ((self.footprint.y / self.line_width).floor() as usize).max(1);
+        let mut lap = Vec::with_capacity(rows * 2);
+        for row in 0..rows {
+            let y = self.position.y + (row as Real + 0.5) * self.line_width;
+            let (x0, x1) = (self.position.x, self.position.x + self.footprint.x);
+            let (a, b) = if row % 2 == 0 { (x0, x1) } else { (x1, x0) };
+            lap.push(Point3::new(a, y, z));
+            lap.push(Point3::new(b, y, z));
+        }
+        let lap_length: Real = lap.windows(2).map(|w| (w[1] - w[0]).norm()).sum();
+        if lap_length <= 0.0 {
+            return vec![lap[0]];
+        }
+
+        // Position along the back-and-forth path at arc length `s`.
+        let at = |s: Real| -> Point3<Real> {
+            let pass = (s / lap_length).floor();
+            let mut local = s - pass * lap_length;
+            if pass as usize % 2 == 1 {
+                local = lap_length - local;
+            }
+            for w in lap.windows(2) {
+                let step = (w[1] - w[0]).norm();
+                if local <= step {
+                    return w[0] + (w[1] - w[0]) * (local / step.max(Real::EPSILON));
+                }
+                local -= step;
+            }
+            lap[lap.len() - 1]
+        };
+
+        // Start and end points plus every lap corner crossed in between.
+        let corners: Vec<Real> = std::iter::once(0.0)
+            .chain(lap.windows(2).scan(0.0, |acc, w| {
+                *acc += (w[1] - w[0]).norm();
+                Some(*acc)
+            }))
+            .collect();
+        let mut breaks = Vec::new();
+        let first_pass = (from / lap_length).floor() as usize;
+        let last_pass = (to / lap_length).floor() as usize;
+        for pass in first_pass..=last_pass {
+            let base = pass as Real * lap_length;
+            for c in &corners {
+                let s = if pass % 2 == 0 { base + c } else { base + lap_length - c };
+                if s > from && s < to {
+                    breaks.push(s);
+                }
+            }
+        }
+        breaks.sort_by(|a, b| a.total_cmp(b));
+        // The end of one pass and the start of the next are the same corner.
+        breaks.dedup_by(|a, b| (*a - *b).abs() <= LAYER_EPSILON);
+
+        let mut points = vec![at(from)];
+        points.extend(breaks.into_iter().map(at));
+        points.push(at(to));
+        points
+    }
+}
+
+/// Measured bed height map for slicer-side mesh leveling.
+#[derive(Debug, Clone)]
+pub struct BedMesh {
+    /// XY position of the first probe point, `offsets[0][0]`.
+    pub origin: Point2<Real>,
+    /// Distance between probe points along X and Y.
+    pub spacing: Vector2<Real>,
+    /// Z offsets, one row per Y step, each row holding one value per X step.
+    pub offsets: Vec<Vec<Real>>,
+    /// Number of bottom layers that follow the mesh. The first gets the full
+    /// offset and later ones progressively less, reaching zero at this layer.
+    pub fade_layers: usize,
+}
+
+impl BedMesh {
+    /// Bilinearly interpolated offset at `(x, y)`, clamped to the probed area.
+    pub fn offset_at(&self, x: Real, y: Real) -> Real {
+        let rows = self.offsets.len();
+        let cols = self.offsets.first().map_or(0, |row| row.len());
+        if rows == 0 || cols == 0 {
+            return 0.0;
+        }
+        let cell = |value: Real, origin: Real, spacing: Real, count: usize| -> (usize, Real) {
+            if count < 2 || spacing <= 0.0 {
+                return (0, 0.0);
+            }
+            let t = ((value - origin) / spacing).clamp(0.0, (count - 1) as Real);
+            let i = (t.floor() as usize).min(count - 2);
+            (i, t - i as Real)
+        };
+        let (i, tx) = cell(x, self.origin.x, self.spacing.x, cols);
+        let (j, ty) = cell(y, self.origin.y, self.spacing.y, rows);
+        let at = |row: usize, col: usize| self.offsets[row.min(rows - 1)][col.min(cols - 1)];
+        let bottom = at(j, i) * (1.0 - tx) + at(j, i + 1) * tx;
+        let top = at(j + 1, i) * (1.0 - tx) + at(j + 1, i + 1) * tx;
+        bottom * (1.0 - ty) + top * ty
+    }
+}
+
+/// One boundary contour and the holes cut out of it.
+#[derive(Debug, Clone)]
+pub struct PolygonWithHoles {
+    /// Outer contour, counter-clockwise.
+    pub boundary: ToolpathSegment,
+    /// Holes directly inside `boundary`, clockwise.
+    pub holes: Vec<ToolpathSegment>,
+}
+
+/// The polygons of one layer, as rebuilt by `ToolpathSet::to_layer_polygons`.
+#[derive(Debug, Clone)]
+pub struct LayerPolygons {
+    pub z: Option<Real>,
+    pub polygons: Vec<PolygonWithHoles>,
+}
+
+/// A contour whose winding `ToolpathSet::check_and_fix_orientation` reversed.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct OrientationFix {
+    /// Index of the segment in `ToolpathSet::segments`.
+    pub segment: usize,
+    /// Layer the contour is on.
+    pub layer: Option<Real>,
+    /// Whether the contour is a hole (now clockwise) rather than a boundary (now CCW).
+    pub is_hole: bool,
+}
+
+/// Relative costs used by `ToolpathSet::optimize`.
+#[derive(Debug, Clone, Copy)]
+pub struct OptimizeWeights {
+    /// Cost of a single tool change.
+    pub tool_change: Real,
+    /// Cost per unit of travel distance.
+    pub travel: Real,
+}
+
+impl Default for OptimizeWeights {
+    fn default() -> Self {
+        OptimizeWeights {
+            tool_change: 100.0,
+            travel: 1.0,
+        }
+    }
+}
+
 /// A common trait for any toolpath generator, taking a CSG and producing a set of paths.
 pub trait ToolpathGenerator {
     type Config;
@@ -37,17 +938,151 @@ pub trait ToolpathGenerator {
 pub struct AdditiveConfig {
     pub layer_height: Real,
     pub min_z: Real,
+    /// Highest slice Z. Slicing also stops at the model's top, so the default
+    /// (infinity) slices the whole model.
     pub max_z: Real,
+    /// Print bridges after the perimeters/solid fill of the same layer.
+    pub bridges_last: bool,
+    /// Weld coincident contours of touching bodies into a single wall.
+    pub weld_bodies: bool,
+    /// Model-to-machine transform applied to the finished toolpaths.
+    pub machine_transform: Option<Matrix4<Real>>,
+    /// Fast, coarse preview for interactive tuning: slices at several times the
+    /// layer height and simplifies contours heavily. Not meant for production output.
+    pub preview: bool,
+    /// Only generate toolpaths inside this XY polygon (Z of its points is ignored).
+    pub region_mask: Option<Vec<Point3<Real>>>,
+    /// Bed height map the first layers should follow, applied in machine coordinates.
+    pub bed_mesh: Option<BedMesh>,
+    /// What `additive_pipeline` does with a model taller than `max_z`.
+    pub height_policy: HeightPolicy,
     // You could add nozzle diameter, infill %, speeds, etc.
 }
 
+impl Default for AdditiveConfig {
+    fn default() -> Self {
+        AdditiveConfig {
+            layer_height: 0.2,
+            min_z: 0.0,
+            max_z: Real::INFINITY,
+            bridges_last: true,
+            weld_bodies: false,
+            machine_transform: None,
+            preview: false,
+            region_mask: None,
+            bed_mesh: None,
+            height_policy: HeightPolicy::Warn,
+        }
+    }
+}
+
+/// How a layer that would print faster than the minimum layer time is compensated.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MinLayerTimeMode {
+    /// Slow the feed down; the fan only helps once `min_feed_scale` is reached.
+    Feed,
+    /// Keep the feed and raise the fan instead.
+    Fan,
+    /// Slow the feed (down to `min_feed_scale`) and raise the fan together.
+    Both,
+}
+
+/// Minimum layer time enforcement for small, fast layers.
+#[derive(Debug, Clone)]
+pub struct CoolingConfig {
+    /// Shortest time, in seconds, a layer is allowed to take.
+    pub min_layer_time: Real,
+    pub mode: MinLayerTimeMode,
+    /// Max-slowdown floor as a fraction of the nominal feed (e.g. 0.3 = 30%).
+    pub min_feed_scale: Real,
+    /// Fan speed (0..1) used on layers that need no help.
+    pub fan_min: Real,
+    /// Fan speed (0..1) used when a layer takes (close to) no time at all.
+    pub fan_max: Real,
+}
+
+/// Per-layer result of minimum layer time enforcement.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct LayerCooling {
+    pub z: Real,
+    /// Multiplier applied to the nominal feed for this layer.
+    pub feed_scale: Real,
+    /// Fan speed (0..1) for this layer.
+    pub fan: Real,
+}
+
+impl CoolingConfig {
+    /// Compensation for a single layer that takes `layer_time` seconds at nominal feed.
+    pub fn cooling_for(&self, z: Real, layer_time: Real) -> LayerCooling {
+        if layer_time >= self.min_layer_time || self.min_layer_time <= 0.0 {
+            return LayerCooling { z, feed_scale: 1.0, fan: self.fan_min };
+        }
+
+        // Slowing down by `layer_time / min_layer_time` would exactly fill the layer
+        // time, but never go below the configured floor.
+        let slowed = (layer_time / self.min_layer_time).max(self.min_feed_scale).min(1.0);
+        // Fan ramps linearly with how much of the minimum time is still missing.
+        let fan_for = |time: Real| {
+            let shortfall = (1.0 - time / self.min_layer_time).clamp(0.0, 1.0);
+            self.fan_min + (self.fan_max - self.fan_min) * shortfall
+        };
+
+        let (feed_scale, fan) = match self.mode {
+            MinLayerTimeMode::Feed => (slowed, fan_for(layer_time / slowed)),
+            MinLayerTimeMode::Fan => (1.0, fan_for(layer_time)),
+            MinLayerTimeMode::Both => (slowed, fan_for(layer_time)),
+        };
+        LayerCooling { z, feed_scale, fan }
+    }
+}
+
 /// Configuration for subtractive manufacturing (CNC).
 #[derive(Debug, Clone)]
 pub struct SubtractiveConfig {
     pub step_down: Real,
     pub min_z: Real,
+    /// Highest cutting level. Cutting also starts no higher than the model's
+    /// top, so the default (infinity) cuts the whole model.
     pub max_z: Real,
-    // You could add tool diameter, offset strategies, step-over, etc.
+    /// Model-to-machine transform applied to the finished toolpaths.
+    pub machine_transform: Option<Matrix4<Real>>,
+    /// Ramp Z continuously over each revolution of the contour instead of stepping
+    /// between levels, producing one helical segment. Only applies when every level
+    /// has a single contour; otherwise the stepped passes are kept.
+    pub spiral_z: bool,
+    /// Cutter diameter. Contours are offset by its radius away from material, so
+    /// boundaries (bosses) are cut from outside and holes from inside. `0.0`
+    /// keeps the raw cross-section. Open profiles have no material side and are
+    /// not compensated: the tool center follows them as sliced.
+    pub tool_diameter: Real,
+    /// Finishing allowance: material left on every wall for a later finishing
+    /// pass, added to the tool radius offset. `0.0` cuts to the final boundary.
+    pub stock_to_leave: Real,
+    /// Only generate toolpaths inside this XY polygon (Z of its points is ignored).
+    /// Tool-center paths are clipped, so the cutter may still reach past the
+    /// mask edge by its radius.
+    pub region_mask: Option<Vec<Point3<Real>>>,
+    /// Lead-in/out length added at both free ends of open profiles (see
+    /// `ToolpathSegment::add_leads`). `0.0` disables it. Loops cut open by
+    /// `region_mask` get no leads.
+    pub lead_length: Real,
+    // You could add offset strategies, step-over, etc.
+}
+
+impl Default for SubtractiveConfig {
+    fn default() -> Self {
+        SubtractiveConfig {
+            step_down: 1.0,
+            min_z: 0.0,
+            max_z: Real::INFINITY,
+            machine_transform: None,
+            spiral_z: false,
+            tool_diameter: 0.0,
+            stock_to_leave: 0.0,
+            region_mask: None,
+            lead_length: 0.0,
+        }
+    }
 }
 
 /// Toolpath generator for additive layer-based slicing.
@@ -58,10 +1093,19 @@ impl ToolpathGenerator for AdditiveToolpathGenerator {
 
     fn generate_toolpaths(&self, model: &CSG, cfg: &AdditiveConfig) -> ToolpathSet {
         let mut all_segments = Vec::new();
+
+        // Previews merge several layers into one and simplify with that coarser height.
+        let layer_height = if cfg.preview {
+            cfg.layer_height * PREVIEW_LAYER_MULTIPLIER
+        } else {
+            cfg.layer_height
+        };
         
-        // 1) We iterate over z-layers from min_z up to max_z in increments of cfg.layer_height
+        // 1) We iterate over z-layers from min_z up to max_z (or the model's top,
+        //    whichever is lower) in increments of layer_height
+        let top = cfg.max_z.min(model.bounding_box().maxs.z);
         let mut z = cfg.min_z;
-        while z <= cfg.max_z + 1e-7 {
+        while z <= top + 1e-7 {
             // 2) Slice the CSG with a plane z=0, but we first translate the model 
             //    so that plane is at `z` in the original coordinate system.
             //    Then we call `project(cut_at_z0=true)` to get the cross-section.
@@ -75,32 +1119,78 @@ impl ToolpathGenerator for AdditiveToolpathGenerator {
             // 3) Convert cross-section polygons into polylines.
             //    Each polygon is in Z=0 after slicing. We'll then translate back up by +z.
             for poly in &cross_section.polygons {
-                if poly.vertices.len() < 3 {
+                let Some(mut segment) = polygon_to_segment(poly, z) else {
                     continue;
+                };
+                if cfg.preview {
+                    segment.simplify(layer_height);
                 }
-                
-                // Convert the polygon (assumed planar at z=0) to a 2D polyline
-                let pline2d = poly.to_polyline();
-                // Then convert that 2D polyline to a 3D path at z
-                let mut points_3d = Vec::new();
-                for v2d in pline2d.vertex_data {
-                    points_3d.push(Point3::new(v2d.x, v2d.y, z));
-                }
-                // Form a path segment
-                all_segments.push(ToolpathSegment {
-                    points: points_3d,
-                });
+                all_segments.push(segment);
             }
 
-            z += cfg.layer_height;
+            z += layer_height;
         }
         
-        ToolpathSet {
+        let mut set = ToolpathSet {
             segments: all_segments,
+        };
+        if cfg.weld_bodies {
+            set.weld_coincident_contours(CONTOUR_EPSILON);
         }
+        if let Some(mask) = &cfg.region_mask {
+            set.clip_to_region(mask);
+        }
+        set.order_sparse_before_solid();
+        if cfg.bridges_last {
+            set.order_bridges_last();
+        }
+        if let Some(matrix) = &cfg.machine_transform {
+            set.transform(matrix);
+        }
+        if let Some(mesh) = &cfg.bed_mesh {
+            set.apply_bed_mesh(mesh);
+        }
+        set
     }
 }
 
+/// Run the whole additive flow in one call: check the model fits under `max_z`
+/// (failing or carrying on as `additive_cfg.height_policy` says), slice it,
+/// optimize travel (keeping the infill and bridge ordering `additive_cfg` asks
+/// for), then emit G-code. `gcode_cfg.layer_height` falls back to
+/// `additive_cfg.layer_height` when unset, and a preview slice is marked as such.
+/// A model clipped at `max_z` is reported in a `; WARNING` line at the top of
+/// the G-code.
+pub fn additive_pipeline(
+    model: &CSG,
+    additive_cfg: &AdditiveConfig,
+    gcode_cfg: &GcodeConfig,
+) -> Result<(ToolpathSet, String), ToolpathError> {
+    let clip = check_model_height(model, additive_cfg.max_z, additive_cfg.height_policy)?;
+    let mut set = AdditiveToolpathGenerator.generate_toolpaths(model, additive_cfg);
+    set.optimize(OptimizeWeights::default());
+    set.order_sparse_before_solid();
+    if additive_cfg.bridges_last {
+        set.order_bridges_last();
+    }
+    let gcode_cfg = GcodeConfig {
+        layer_height: gcode_cfg.layer_height.or(Some(additive_cfg.layer_height)),
+        preview: gcode_cfg.preview || additive_cfg.preview,
+        ..gcode_cfg.clone()
+    };
+    let mut gcode = to_gcode(&set, &gcode_cfg)?;
+    if let Some(clip) = &clip {
+        gcode.insert_str(
+            0,
+            &format!(
+                "; WARNING: model top {} is above max_z {}; the top {} is not sliced\n",
+                clip.model_max_z, clip.max_z, clip.clipped
+            ),
+        );
+    }
+    Ok((set, gcode))
+}
+
 /// Toolpath generator for subtractive z-level (very naive approach).
 pub struct SubtractiveToolpathGenerator;
 
@@ -114,36 +1204,808 @@ impl ToolpathGenerator for SubtractiveToolpathGenerator {
         // We'll produce "contour passes" at multiple Z levels. 
         // Real CNC often does waterline offsets or more advanced strategies.
 
-        let mut z = cfg.max_z;
-        // Move downward in step_down increments
+        // Start at max_z or the model's top, whichever is lower, and move
+        // downward in step_down increments
+        let mut z = cfg.max_z.min(model.bounding_box().maxs.z);
         while z >= cfg.min_z - 1e-7 {
             // "Contour" at this Z means: 
             //  1) Intersect the part with plane z in the same manner as additive. 
-            //  2) Possibly offset outward by tool radius to get a cutting path, etc.
-            // For simplicity, just show the direct cross-section.
+            //  2) Offset the contours by the tool radius (plus stock to leave) so the
+            //     cutter center rides outside bosses and inside holes.
 
             let model_shifted = model.translate(Vector3::new(0.0, 0.0, -z));
             let cross_section = model_shifted.slice(Plane { normal: Vector3::z(), w: 0.0 });
 
-            for poly in &cross_section.polygons {
-                if poly.vertices.len() < 3 {
-                    continue;
-                }
-                let pline2d = poly.to_polyline();
-                let mut points_3d = Vec::new();
-                for v2d in pline2d.vertex_data {
-                    points_3d.push(Point3::new(v2d.x, v2d.y, z));
-                }
-                all_segments.push(ToolpathSegment {
-                    points: points_3d,
-                });
+            let mut level: Vec<ToolpathSegment> = cross_section
+                .polygons
+                .iter()
+                .filter_map(|poly| polygon_to_segment(poly, z))
+                .collect();
+
+            // Turn part contours into tool-center paths: outside bosses, inside
+            // holes, standing off by the finishing allowance as well.
+            let offset = cfg.tool_diameter * 0.5 + cfg.stock_to_leave;
+            if offset > 0.0 {
+                level = contour::offset_away_from_material(&level, offset, CONTOUR_EPSILON);
             }
+            all_segments.extend(level);
 
             z -= cfg.step_down;
         }
 
-        ToolpathSet {
+        let mut set = ToolpathSet {
             segments: all_segments,
+        };
+        // Leads go on before clipping: only profiles open as sliced get them, and
+        // they are cut back at the mask like any other path.
+        for seg in &mut set.segments {
+            seg.add_leads(cfg.lead_length);
+        }
+        if let Some(mask) = &cfg.region_mask {
+            set.clip_to_region(mask);
+        }
+        if cfg.spiral_z {
+            if let Some(helix) = spiral_contour(&set.segments) {
+                set.segments = vec![helix];
+            }
+        }
+        if let Some(matrix) = &cfg.machine_transform {
+            set.transform(matrix);
+        }
+        set
+    }
+}
+
+/// Join top-down contour levels (one loop per level) into a single helical path.
+/// Each revolution descends from its level to the next one, spread over the loop
+/// length; the bottom level is finished with a flat lap. Returns `None` if the
+/// levels aren't one loop each.
+fn spiral_contour(levels: &[ToolpathSegment]) -> Option<ToolpathSegment> {
+    let mut loops = Vec::with_capacity(levels.len());
+    for (i, seg) in levels.iter().enumerate() {
+        let z = seg.z()?;
+        let same_level = |other: &ToolpathSegment| other.z().is_some_and(|oz| (oz - z).abs() <= LAYER_EPSILON);
+        let verts = contour::loop_vertices(seg);
+        if !seg.closed || verts.len() < 3 || levels.get(i + 1).is_some_and(same_level) {
+            return None;
+        }
+        // Walk every level the same way round so the helix doesn't reverse.
+        let mut verts = verts.to_vec();
+        if seg.area_xy_closed() < 0.0 {
+            verts.reverse();
+        }
+        loops.push((z, verts));
+    }
+
+    let mut points: Vec<Point3<Real>> = Vec::new();
+    for i in 0..loops.len() {
+        let (z, verts) = &loops[i];
+        // Start each lap at the vertex nearest to where the previous one ended.
+        let start = points.last().map_or(0, |end| {
+            (0..verts.len())
+                .min_by(|&a, &b| {
+                    let da = (verts[a].x - end.x).powi(2) + (verts[a].y - end.y).powi(2);
+                    let db = (verts[b].x - end.x).powi(2) + (verts[b].y - end.y).powi(2);
+                    da.total_cmp(&db)
+                })
+                .unwrap_or(0)
+        });
+        let lap: Vec<Point3<Real>> = (0..=verts.len()).map(|k| verts[(start + k) % verts.len()]).collect();
+        let lap_length: Real = lap.windows(2).map(|w| (w[1] - w[0]).norm()).sum();
+        let next_z = loops.get(i + 1).map_or(*z, |(nz, _)| *nz);
+
+        let mut travelled = 0.0;
+        for (k, p) in lap.iter().enumerate() {
+            if k > 0 {
+                travelled += (lap[k] - lap[k - 1]).norm();
+            }
+            let t = if lap_length > 0.0 { travelled / lap_length } else { 0.0 };
+            let point = Point3::new(p.x, p.y, z + (next_z - z) * t);
+            // Lap joins and repeated slice vertices would otherwise become zero-length moves.
+            if points.last().is_none_or(|last| (point - last).norm() > LAYER_EPSILON) {
+                points.push(point);
+            }
+        }
+    }
+
+    Some(ToolpathSegment {
+        points,
+        kind: SegmentKind::Perimeter,
+        closed: false,
+        tool: levels[0].tool,
+        layer_z: None,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn path(points: &[(Real, Real)], z: Real, closed: bool) -> ToolpathSegment {
+        ToolpathSegment {
+            points: points.iter().map(|&(x, y)| Point3::new(x, y, z)).collect(),
+            kind: SegmentKind::Perimeter,
+            closed,
+            tool: 0,
+            layer_z: Some(z),
+        }
+    }
+
+    /// Closed CCW square with its corner at (x0, y0), at height `z`.
+    pub(crate) fn square_at(x0: Real, y0: Real, size: Real, z: Real) -> ToolpathSegment {
+        path(&[(x0, y0), (x0 + size, y0), (x0 + size, y0 + size), (x0, y0 + size)], z, true)
+    }
+
+    /// The 10x10 square at the origin, at height `z`.
+    pub(crate) fn square(z: Real) -> ToolpathSegment {
+        square_at(0.0, 0.0, 10.0, z)
+    }
+
+    #[test]
+    fn area_xy_closed_includes_closing_edge_of_open_path() {
+        let open = path(&[(5.0, 5.0), (15.0, 5.0), (15.0, 15.0), (5.0, 15.0)], 0.0, false);
+        assert!((open.area_xy_closed() - 100.0).abs() < 1e-9);
+    }
+
+    #[test]
+    fn net_area_xy_of_annulus_is_outer_minus_inner() {
+        let outer = square(0.0);
+        let hole = path(&[(3.0, 3.0), (3.0, 7.0), (7.0, 7.0), (7.0, 3.0)], 0.0, true);
+        let stray = path(&[(20.0, 0.0), (30.0, 0.0), (30.0, 10.0)], 0.0, false);
+        assert!((net_area_xy(&[outer, hole, stray]) - 84.0).abs() < 1e-9);
+    }
+
+    fn kinds(set: &ToolpathSet) -> Vec<SegmentKind> {
+        set.segments.iter().map(|seg| seg.kind).collect()
+    }
+
+    fn of_kind(kind: SegmentKind, x: Real) -> ToolpathSegment {
+        ToolpathSegment { kind, ..path(&[(x, 0.0), (x, 5.0)], 1.0, false) }
+    }
+
+    #[test]
+    fn order_sparse_before_solid_groups_solid_infill() {
+        use SegmentKind::*;
+        let mut set = ToolpathSet {
+            segments: vec![
+                of_kind(Perimeter, 0.0),
+                of_kind(SolidInfill, 1.0),
+                of_kind(Infill, 2.0),
+                of_kind(Perimeter, 3.0),
+                of_kind(SolidInfill, 4.0),
+                of_kind(Infill, 5.0),
+            ],
+        };
+        set.order_sparse_before_solid();
+        assert_eq!(kinds(&set), vec![Perimeter, Infill, Infill, SolidInfill, SolidInfill, Perimeter]);
+        let xs: Vec<Real> = set.segments.iter().map(|seg| seg.points[0].x).collect();
+        assert_eq!(xs, vec![0.0, 2.0, 5.0, 1.0, 4.0, 3.0]);
+    }
+
+    #[test]
+    fn default_additive_config_slices_the_whole_model() {
+        let cube = CSG::cube(10.0, 10.0, 2.0, None);
+        let set = AdditiveToolpathGenerator.generate_toolpaths(&cube, &AdditiveConfig::default());
+        let top = set.segments.iter().filter_map(|seg| seg.layer()).fold(Real::MIN, Real::max);
+        assert!((top - 2.0).abs() < 1e-6);
+        assert_eq!(set.layer_ranges().len(), 11);
+    }
+
+    #[test]
+    fn weld_bodies_drops_the_wall_between_touching_cubes() {
+        // Two abutting cubes, combined without a union.
+        let left = CSG::cube(10.0, 10.0, 2.0, None);
+        let right = CSG::cube(10.0, 10.0, 2.0, None).translate(Vector3::new(10.0, 0.0, 0.0));
+        let bodies = CSG::from_polygons(&[left.polygons, right.polygons].concat());
+        let cfg = AdditiveConfig { layer_height: 1.0, weld_bodies: true, ..Default::default() };
+        let set = AdditiveToolpathGenerator.generate_toolpaths(&bodies, &cfg);
+        assert_eq!(set.segments.len(), 3);
+        for seg in &set.segments {
+            assert!(seg.closed);
+            assert!((seg.length() - 60.0).abs() < 1e-9, "layer {:?} is {} long", seg.layer_z, seg.length());
+            assert!((seg.area_xy_closed().abs() - 200.0).abs() < 1e-9);
+        }
+    }
+
+    #[test]
+    fn default_subtractive_config_cuts_the_whole_model() {
+        let cube = CSG::cube(10.0, 10.0, 5.0, None);
+        let set = SubtractiveToolpathGenerator.generate_toolpaths(&cube, &SubtractiveConfig::default());
+        let levels: Vec<Real> = set.segments.iter().filter_map(|seg| seg.layer()).collect();
+        assert_eq!(levels, vec![5.0, 4.0, 3.0, 2.0, 1.0, 0.0]);
+    }
+
+    #[test]
+    fn order_bridges_last_moves_bridges_to_the_end_of_their_layer() {
+        use SegmentKind::*;
+        let mut set = ToolpathSet {
+            segments: vec![
+                of_kind(Perimeter, 0.0),
+                of_kind(Bridge, 1.0),
+                of_kind(SolidInfill, 2.0),
+                of_kind(Bridge, 3.0),
+                ToolpathSegment { layer_z: Some(2.0), ..of_kind(Perimeter, 4.0) },
+            ],
+        };
+        set.order_bridges_last();
+        assert_eq!(kinds(&set), vec![Perimeter, SolidInfill, Bridge, Bridge, Perimeter]);
+        let xs: Vec<Real> = set.segments.iter().map(|seg| seg.points[0].x).collect();
+        assert_eq!(xs, vec![0.0, 2.0, 1.0, 3.0, 4.0]);
+    }
+
+    #[test]
+    fn additive_pipeline_slices_and_emits_a_cube() {
+        let cube = CSG::cube(10.0, 10.0, 10.0, None);
+        let cfg = AdditiveConfig { layer_height: 1.0, ..Default::default() };
+        let (set, gcode) = additive_pipeline(&cube, &cfg, &GcodeConfig::default()).unwrap();
+        assert_eq!(set.layer_ranges().len(), 11);
+        assert!(gcode.lines().filter(|l| l.starts_with("G1 ")).count() >= 44);
+        let allowed = ["G0", "G1", "G21", "G90"].iter().map(|c| c.to_string()).collect();
+        assert!(validate_gcode(&gcode, &allowed).is_empty());
+    }
+
+    #[test]
+    fn additive_pipeline_follows_the_height_policy() {
+        let cube = CSG::cube(10.0, 10.0, 10.0, None);
+        let lenient = AdditiveConfig { layer_height: 1.0, max_z: 5.0, ..Default::default() };
+        assert_eq!(lenient.height_policy, HeightPolicy::default());
+        let (set, gcode) = additive_pipeline(&cube, &lenient, &GcodeConfig::default()).unwrap();
+        assert_eq!(set.layer_ranges().len(), 6);
+        assert!(gcode.starts_with("; WARNING: model top 10 is above max_z 5; the top 5 is not sliced\n"));
+
+        let strict = AdditiveConfig { height_policy: HeightPolicy::Error, ..lenient };
+        assert_eq!(
+            additive_pipeline(&cube, &strict, &GcodeConfig::default()).unwrap_err(),
+            ToolpathError::ModelTooTall { model_max_z: 10.0, max_z: 5.0 }
+        );
+
+        let fits = AdditiveConfig { max_z: 10.0, ..strict };
+        let (_, gcode) = additive_pipeline(&cube, &fits, &GcodeConfig::default()).unwrap();
+        assert!(!gcode.contains("WARNING"));
+    }
+
+    #[test]
+    fn cooling_for_both_slows_the_feed_and_raises_the_fan() {
+        let cfg = CoolingConfig {
+            min_layer_time: 10.0,
+            mode: MinLayerTimeMode::Both,
+            min_feed_scale: 0.3,
+            fan_min: 0.2,
+            fan_max: 1.0,
+        };
+        let close = |a: Real, b: Real| (a - b).abs() < 1e-12;
+        let half = cfg.cooling_for(1.0, 5.0);
+        assert!(close(half.feed_scale, 0.5) && close(half.fan, 0.6));
+        assert!(half.feed_scale < 1.0 && half.fan > cfg.fan_min);
+
+        // Past the feed floor only the fan keeps compensating.
+        let (fast, faster) = (cfg.cooling_for(1.0, 2.0), cfg.cooling_for(1.0, 1.0));
+        assert_eq!((fast.feed_scale, faster.feed_scale), (0.3, 0.3));
+        assert!(close(fast.fan, 0.84) && close(faster.fan, 0.92));
+
+        let fan_only = CoolingConfig { mode: MinLayerTimeMode::Fan, ..cfg.clone() }.cooling_for(1.0, 5.0);
+        assert!(fan_only.feed_scale == 1.0 && close(fan_only.fan, 0.6));
+        let feed_only = CoolingConfig { mode: MinLayerTimeMode::Feed, ..cfg.clone() }.cooling_for(1.0, 5.0);
+        assert!(close(feed_only.feed_scale, 0.5) && close(feed_only.fan, cfg.fan_min));
+        assert_eq!(cfg.cooling_for(1.0, 12.0), LayerCooling { z: 1.0, feed_scale: 1.0, fan: 0.2 });
+    }
+
+    #[test]
+    fn subtractive_offsets_plate_hole_and_boss() {
+        // 20x20x2 plate with a 4x4 through hole, plus a 4x4 boss standing on it.
+        let hole = CSG::cube(4.0, 4.0, 4.0, None).translate(Vector3::new(3.0, 3.0, -1.0));
+        let boss = CSG::cube(4.0, 4.0, 4.0, None).translate(Vector3::new(12.0, 12.0, 2.0));
+        let part = CSG::cube(20.0, 20.0, 2.0, None).difference(&hole).union(&boss);
+        let cfg = SubtractiveConfig { max_z: 4.0, min_z: 1.0, step_down: 3.0, tool_diameter: 2.0, ..Default::default() };
+        let set = SubtractiveToolpathGenerator.generate_toolpaths(&part, &cfg);
+        let areas: Vec<(Real, Real)> = set
+            .segments
+            .iter()
+            .map(|seg| (seg.z().unwrap(), seg.area_xy_closed()))
+            .collect();
+        // Boss and outer wall grow by the radius (rounded corners); the hole shrinks.
+        let expected = [(4.0, 16.0 + 16.0 + PI), (1.0, 400.0 + 80.0 + PI), (1.0, -4.0)];
+        assert_eq!(areas.len(), expected.len());
+        for ((z, area), (want_z, want_area)) in areas.iter().zip(expected) {
+            assert_eq!(*z, want_z);
+            assert!((area - want_area).abs() < 0.1, "area {area} != {want_area}");
+        }
+    }
+
+    #[test]
+    fn region_mask_keeps_every_point_inside_the_mask() {
+        // The mask covers the left half of the cube, its edge at x = 5.
+        let cube = CSG::cube(10.0, 10.0, 4.0, None);
+        let mask: Vec<Point3<Real>> = [(-5.0, -5.0), (5.0, -5.0), (5.0, 15.0), (-5.0, 15.0)]
+            .iter()
+            .map(|&(x, y)| Point3::new(x, y, 0.0))
+            .collect();
+        let additive = AdditiveToolpathGenerator.generate_toolpaths(
+            &cube,
+            &AdditiveConfig { layer_height: 1.0, region_mask: Some(mask.clone()), ..Default::default() },
+        );
+        let subtractive = SubtractiveToolpathGenerator.generate_toolpaths(
+            &cube,
+            &SubtractiveConfig { tool_diameter: 2.0, lead_length: 3.0, region_mask: Some(mask), ..Default::default() },
+        );
+        for set in [additive, subtractive] {
+            assert_eq!(set.layer_ranges().len(), 5);
+            let xs: Vec<Real> = set.segments.iter().flat_map(|seg| seg.points.iter().map(|p| p.x)).collect();
+            let max_x = xs.iter().fold(Real::MIN, |a, &b| a.max(b));
+            assert!(max_x <= 5.0 + 1e-9, "point past the mask at x = {max_x}");
+            assert!(xs.iter().any(|&x| (x - 5.0).abs() < 1e-9));
+        }
+    }
+
+    #[test]
+    fn polygon_to_segment_lifts_triangle_to_layer() {
+        let vertex = |x, y| Vertex::new(Point3::new(x, y, 0.0), Vector3::z());
+        let triangle: Polygon<()> =
+            Polygon::new(vec![vertex(0.0, 0.0), vertex(4.0, 0.0), vertex(0.0, 3.0)], false, None);
+        let seg = polygon_to_segment(&triangle, 5.0).unwrap();
+        assert!(seg.closed);
+        assert_eq!(seg.layer_z, Some(5.0));
+        assert_eq!(seg.points, vec![Point3::new(0.0, 0.0, 5.0), Point3::new(4.0, 0.0, 5.0), Point3::new(0.0, 3.0, 5.0)]);
+        assert_eq!(seg.length(), 12.0);
+    }
+
+    #[test]
+    fn adhesion_report_flags_top_heavy_part() {
+        let cfg = AdditiveConfig { layer_height: 1.0, ..Default::default() };
+        let report = |model: &CSG| {
+            adhesion_report(&AdditiveToolpathGenerator.generate_toolpaths(model, &cfg), model, 3.0)
+        };
+        let cube = report(&CSG::cube(10.0, 10.0, 10.0, None));
+        assert!(cube.com_over_footprint && cube.stable);
+        // An ordinary upright block stays within the limit.
+        let block = report(&CSG::cube(10.0, 10.0, 25.0, None));
+        assert!((block.slenderness - 1.25).abs() < 1e-6);
+        assert!(block.stable);
+
+        // Symmetric T: a 2x2 stem carrying a 20x20 slab, balanced but top-heavy.
+        let stem = CSG::cube(2.0, 2.0, 10.0, None).translate(Vector3::new(9.0, 9.0, 0.0));
+        let slab = CSG::cube(20.0, 20.0, 2.0, None).translate(Vector3::new(0.0, 0.0, 10.0));
+        let tee = report(&stem.union(&slab));
+        assert!(tee.com_over_footprint);
+        assert!((tee.footprint_area - 4.0).abs() < 1e-6);
+        assert!(tee.slenderness > 5.0);
+        assert!(!tee.stable);
+    }
+
+    #[test]
+    fn adhesion_report_flags_overhang_outside_the_footprint() {
+        // A 4x4 post carrying a 20x4 slab that reaches out to one side.
+        let post = CSG::cube(4.0, 4.0, 2.0, None);
+        let slab = CSG::cube(20.0, 4.0, 2.0, None).translate(Vector3::new(0.0, 0.0, 2.0));
+        let part = post.union(&slab);
+        let cfg = AdditiveConfig { layer_height: 1.0, ..Default::default() };
+        let set = AdditiveToolpathGenerator.generate_toolpaths(&part, &cfg);
+        let report = adhesion_report(&set, &part, Real::INFINITY);
+        assert!((report.footprint_area - 16.0).abs() < 1e-6);
+        assert!((report.center_of_mass.x - 26.0 / 3.0).abs() < 1e-6);
+        assert!(!report.com_over_footprint);
+        assert!(!report.stable);
+    }
+
+    #[test]
+    fn preview_is_coarser_and_marked_in_gcode() {
+        let sphere = CSG::sphere(10.0, 16, 8, None).translate(Vector3::new(0.0, 0.0, 10.0));
+        let full = AdditiveConfig { layer_height: 1.0, ..Default::default() };
+        let preview = AdditiveConfig { preview: true, ..full.clone() };
+        let points = |set: &ToolpathSet| set.segments.iter().map(|seg| seg.points.len()).sum::<usize>();
+        let (full_set, full_gcode) = additive_pipeline(&sphere, &full, &GcodeConfig::default()).unwrap();
+        let (preview_set, preview_gcode) = additive_pipeline(&sphere, &preview, &GcodeConfig::default()).unwrap();
+        assert!(preview_set.segments.len() * 3 < full_set.segments.len());
+        assert!(points(&preview_set) * 3 < points(&full_set));
+        assert!(preview_gcode.starts_with("; PREVIEW - not for production\n"));
+        assert!(!full_gcode.contains("PREVIEW"));
+        // Same shape, roughly: every extent within one preview layer, never beyond the full slice.
+        let bounds = |set: &ToolpathSet| {
+            let points = set.segments.iter().flat_map(|seg| &seg.points);
+            let low = points.clone().fold(Point3::new(Real::MAX, Real::MAX, Real::MAX), |b, p| b.inf(p));
+            let high = points.fold(Point3::new(Real::MIN, Real::MIN, Real::MIN), |b, p| b.sup(p));
+            (low, high)
+        };
+        let ((full_low, full_high), (preview_low, preview_high)) = (bounds(&full_set), bounds(&preview_set));
+        let tolerance = full.layer_height * PREVIEW_LAYER_MULTIPLIER;
+        for axis in 0..3 {
+            let (shrink_low, shrink_high) = (preview_low[axis] - full_low[axis], full_high[axis] - preview_high[axis]);
+            assert!((0.0..=tolerance).contains(&shrink_low), "axis {axis} low moved by {shrink_low}");
+            assert!((0.0..=tolerance).contains(&shrink_high), "axis {axis} high moved by {shrink_high}");
+        }
+    }
+
+    #[test]
+    fn length_by_kind_splits_a_filled_layer() {
+        let filled = |z: Real| {
+            let mut layer = vec![square(z)];
+            for y in [2.5, 5.0, 7.5] {
+                layer.push(ToolpathSegment { kind: SegmentKind::Infill, ..path(&[(0.0, y), (10.0, y)], z, false) });
+            }
+            layer
+        };
+        let set = ToolpathSet { segments: [filled(0.2), filled(0.4)].concat() };
+        let totals = set.length_by_kind();
+        assert_eq!(totals.len(), 2);
+        assert_eq!(totals[&SegmentKind::Perimeter], 80.0);
+        assert_eq!(totals[&SegmentKind::Infill], 60.0);
+
+        let layers = set.layer_length_by_kind();
+        assert_eq!(layers.iter().map(|(z, _)| *z).collect::<Vec<_>>(), vec![0.2, 0.4]);
+        for (_, lengths) in &layers {
+            assert_eq!(lengths[&SegmentKind::Perimeter], 40.0);
+            assert_eq!(lengths[&SegmentKind::Infill], 30.0);
+        }
+    }
+
+    #[test]
+    fn machine_transform_rotates_then_translates() {
+        let cube = CSG::cube(10.0, 4.0, 2.0, None);
+        let turn = Matrix4::new_translation(&Vector3::new(100.0, 50.0, 5.0))
+            * Matrix4::from_axis_angle(&Vector3::z_axis(), PI / 2.0);
+        let cfg = AdditiveConfig { layer_height: 1.0, ..Default::default() };
+        let plain = AdditiveToolpathGenerator.generate_toolpaths(&cube, &cfg);
+        let moved = AdditiveToolpathGenerator
+            .generate_toolpaths(&cube, &AdditiveConfig { machine_transform: Some(turn), ..cfg });
+        assert_eq!(plain.segments.len(), moved.segments.len());
+        for (a, b) in plain.segments.iter().zip(&moved.segments) {
+            for (p, q) in a.points.iter().zip(&b.points) {
+                let expected = Point3::new(100.0 - p.y, 50.0 + p.x, p.z + 5.0);
+                assert!((q - expected).norm() < 1e-9, "{q} != {expected}");
+            }
+        }
+        let xs = moved.segments.iter().flat_map(|seg| seg.points.iter().map(|p| p.x));
+        assert_eq!(xs.fold(Real::MAX, Real::min), 96.0);
+    }
+
+    #[test]
+    fn spiral_z_cuts_a_cylinder_in_one_helix() {
+        let cylinder = CSG::cylinder(5.0, 10.0, 24, None);
+        let cfg = SubtractiveConfig { max_z: 9.0, min_z: 1.0, step_down: 2.0, spiral_z: true, ..Default::default() };
+        let set = SubtractiveToolpathGenerator.generate_toolpaths(&cylinder, &cfg);
+        assert_eq!(set.segments.len(), 1);
+        let helix = &set.segments[0];
+        assert!(!helix.closed);
+        assert_eq!(helix.points.first().unwrap().z, 9.0);
+        assert_eq!(helix.points.last().unwrap().z, 1.0);
+        assert!(helix.points.windows(2).all(|w| w[1].z <= w[0].z + 1e-12));
+        // Four descending revolutions plus the flat bottom lap.
+        assert!((helix.length() - 5.0 * 24.0 * 2.0 * 5.0 * (PI / 24.0).sin()).abs() < 0.5);
+
+        // Two loops per level can't form a single helix.
+        let tube = cylinder.difference(&CSG::cylinder(2.0, 10.0, 24, None));
+        let set = SubtractiveToolpathGenerator.generate_toolpaths(&tube, &cfg);
+        assert_eq!(set.segments.len(), 10);
+    }
+
+    #[test]
+    fn optimize_groups_tools_when_changes_are_expensive() {
+        let dash = |x: Real, z: Real, tool: usize| ToolpathSegment { tool, ..path(&[(x, 0.0), (x, 0.1)], z, false) };
+        let set = ToolpathSet {
+            segments: vec![dash(0.0, 1.0, 0), dash(1.0, 1.0, 1), dash(2.0, 1.0, 0), dash(3.0, 1.0, 1), dash(0.0, 2.0, 1)],
+        };
+        let tools = |weights: OptimizeWeights| {
+            let mut set = set.clone();
+            set.optimize(weights);
+            set.segments.iter().map(|seg| (seg.tool, seg.layer_z.unwrap())).collect::<Vec<_>>()
+        };
+        assert_eq!(
+            tools(OptimizeWeights { travel: 1.0, tool_change: 0.0 }),
+            vec![(0, 1.0), (1, 1.0), (0, 1.0), (1, 1.0), (1, 2.0)]
+        );
+        assert_eq!(
+            tools(OptimizeWeights { travel: 1.0, tool_change: 100.0 }),
+            vec![(0, 1.0), (0, 1.0), (1, 1.0), (1, 1.0), (1, 2.0)]
+        );
+    }
+
+    #[test]
+    fn purge_tower_follows_each_tool_change() {
+        let dash = |x: Real, z: Real, tool: usize| ToolpathSegment { tool, ..path(&[(x, 0.0), (x, 5.0)], z, false) };
+        let mut set = ToolpathSet {
+            segments: vec![dash(0.0, 0.2, 0), dash(1.0, 0.2, 1), dash(2.0, 0.2, 0), dash(0.0, 0.4, 0), dash(1.0, 0.4, 1)],
+        };
+        let cfg = PurgeTowerConfig {
+            position: Point2::new(50.0, 50.0),
+            footprint: Vector2::new(10.0, 10.0),
+            purge_volume: 2.0,
+            line_width: 0.5,
+            layer_height: 0.2,
+        };
+        set.add_purge_tower(&cfg);
+        let summary: Vec<(SegmentKind, usize, Real)> =
+            set.segments.iter().map(|seg| (seg.kind, seg.tool, seg.layer_z.unwrap())).collect();
+        use SegmentKind::{Perimeter as P, PurgeTower as T};
+        assert_eq!(
+            summary,
+            vec![(P, 0, 0.2), (T, 1, 0.2), (P, 1, 0.2), (T, 0, 0.2), (P, 0, 0.2), (P, 0, 0.4), (T, 1, 0.4), (P, 1, 0.4)]
+        );
+        for tower in set.segments.iter().filter(|seg| seg.kind == T) {
+            // Each pass lays down exactly the purge volume inside the footprint, at its layer.
+            assert!((tower.length() * cfg.line_width * cfg.layer_height - cfg.purge_volume).abs() < 1e-9);
+            for p in &tower.points {
+                assert!((50.0..=60.0).contains(&p.x) && (50.0..=60.0).contains(&p.y));
+                assert_eq!(Some(p.z), tower.layer_z);
+            }
+        }
+        // The second pass on a layer continues where the first stopped.
+        assert_eq!(set.segments[1].points.last(), set.segments[3].points.first());
+    }
+
+    #[test]
+    fn export_layers_csv_writes_one_row_per_point() {
+        let line = path(&[(0.0, 5.0), (10.0, 5.0)], 0.2, false);
+        let set = ToolpathSet { segments: vec![square(0.2), line, square(0.4)] };
+        let csv = export_layers_csv(&set);
+        let rows: Vec<&str> = csv.lines().collect();
+        assert_eq!(rows.len(), 1 + 4 + 2 + 4);
+        assert_eq!(rows[0], "layer,segment,index,x,y,z");
+        assert_eq!(rows[5], "0,1,0,0,5,0.2");
+        assert_eq!(rows[10], "1,0,3,0,10,0.4");
+    }
+
+    #[test]
+    fn safe_rapid_z_clears_the_model_top() {
+        let raised = CSG::cube(10.0, 10.0, 7.5, None).translate(Vector3::new(0.0, 0.0, 2.0));
+        assert_eq!(safe_rapid_z(&raised, 5.0), 14.5);
+        assert_eq!(safe_rapid_z(&raised, 0.0), 9.5);
+    }
+
+    #[test]
+    fn draped_segments_keep_their_slice_layer() {
+        let cube = CSG::cube(10.0, 10.0, 2.0, None);
+        let cfg = AdditiveConfig {
+            layer_height: 0.5,
+            bed_mesh: Some(BedMesh {
+                origin: Point2::origin(),
+                spacing: Vector2::new(10.0, 10.0),
+                offsets: vec![vec![0.0, 0.3], vec![0.3, 0.6]],
+                fade_layers: 2,
+            }),
+            ..Default::default()
+        };
+        let flat = AdditiveToolpathGenerator.generate_toolpaths(&cube, &AdditiveConfig { bed_mesh: None, ..cfg.clone() });
+        let draped = AdditiveToolpathGenerator.generate_toolpaths(&cube, &cfg);
+        let layers: Vec<Option<Real>> = draped.segments.iter().map(|seg| seg.layer_z).collect();
+        assert_eq!(layers, flat.segments.iter().map(|seg| seg.layer_z).collect::<Vec<_>>());
+        assert_eq!(draped.layer_ranges(), flat.layer_ranges());
+        // The bottom loop no longer lies in one plane, but still reports its slice.
+        let bottom = &draped.segments[0];
+        assert!(bottom.points.iter().any(|p| p.z != bottom.points[0].z));
+        assert_eq!(bottom.layer(), Some(0.0));
+    }
+
+    #[test]
+    fn check_and_fix_orientation_rewinds_wrong_loops() {
+        let mut cw_outer = square(2.0);
+        cw_outer.points.reverse();
+        // Layer 1: correct boundary, hole wound CCW. Layer 2: boundary wound CW.
+        let mut set = ToolpathSet { segments: vec![square(1.0), square_at(3.0, 3.0, 4.0, 1.0), cw_outer] };
+        let fixes = set.check_and_fix_orientation();
+        assert_eq!(
+            fixes,
+            vec![
+                OrientationFix { segment: 1, layer: Some(1.0), is_hole: true },
+                OrientationFix { segment: 2, layer: Some(2.0), is_hole: false },
+            ]
+        );
+        let areas: Vec<Real> = set.segments.iter().map(|seg| seg.area_xy_closed()).collect();
+        assert_eq!(areas, vec![100.0, -16.0, 100.0]);
+        assert!(set.check_and_fix_orientation().is_empty());
+    }
+
+    #[test]
+    fn check_and_fix_orientation_ignores_open_paths_around_a_hole() {
+        let mut hole = square_at(3.0, 3.0, 4.0, 1.0);
+        hole.points.reverse();
+        // An open U of infill around the hole would enclose it if closed implicitly.
+        let infill = ToolpathSegment {
+            kind: SegmentKind::Infill,
+            ..path(&[(1.0, 9.0), (1.0, 1.0), (9.0, 1.0), (9.0, 9.0)], 1.0, false)
+        };
+        let mut set = ToolpathSet { segments: vec![square(1.0), hole, infill] };
+        assert!(set.check_and_fix_orientation().is_empty());
+        assert_eq!(set.segments[1].area_xy_closed(), -16.0);
+    }
+
+    #[test]
+    fn check_model_height_reports_the_clipped_height() {
+        let cube = CSG::cube(10.0, 10.0, 12.0, None);
+        assert_eq!(
+            check_model_height(&cube, 9.5, HeightPolicy::Warn),
+            Ok(Some(HeightClip { model_max_z: 12.0, max_z: 9.5, clipped: 2.5 }))
+        );
+        assert_eq!(
+            check_model_height(&cube, 9.5, HeightPolicy::Error),
+            Err(ToolpathError::ModelTooTall { model_max_z: 12.0, max_z: 9.5 })
+        );
+        assert_eq!(check_model_height(&cube, 12.0, HeightPolicy::Error), Ok(None));
+    }
+
+    #[test]
+    fn bed_mesh_raises_first_layer_and_fades_out() {
+        let mut set = ToolpathSet { segments: vec![square(0.2), square(0.4), square(0.6)] };
+        let mesh = BedMesh {
+            origin: Point2::origin(),
+            spacing: Vector2::new(10.0, 10.0),
+            offsets: vec![vec![0.1, 0.3], vec![0.1, 0.3]],
+            fade_layers: 2,
+        };
+        assert!((mesh.offset_at(5.0, 5.0) - 0.2).abs() < 1e-12);
+        set.apply_bed_mesh(&mesh);
+        let zs = |seg: &ToolpathSegment| seg.points.iter().map(|p| p.z).collect::<Vec<_>>();
+        let close = |a: Vec<Real>, b: [Real; 4]| a.iter().zip(b).all(|(x, y)| (x - y).abs() < 1e-12);
+        assert!(close(zs(&set.segments[0]), [0.3, 0.5, 0.5, 0.3]));
+        assert!(close(zs(&set.segments[1]), [0.45, 0.55, 0.55, 0.45]));
+        assert_eq!(zs(&set.segments[2]), vec![0.6; 4]);
+    }
+
+    #[test]
+    fn layer_concavity_measures_the_notch_of_a_c() {
+        let c_shape = path(
+            &[(0.0, 0.0), (10.0, 0.0), (10.0, 3.0), (4.0, 3.0), (4.0, 7.0), (10.0, 7.0), (10.0, 10.0), (0.0, 10.0)],
+            1.0,
+            true,
+        );
+        assert!((layer_concavity(std::slice::from_ref(&c_shape)) - 24.0).abs() < 1e-9);
+        assert_eq!(layer_concavity(&[square(1.0)]), 0.0);
+    }
+
+    #[test]
+    fn remove_zero_length_moves_drops_duplicate_points() {
+        let mut seg = path(
+            &[(0.0, 0.0), (0.0, 0.0), (5.0, 0.0), (5.0, 1e-9), (5.0, 0.01), (5.0, 5.0), (5.0, 5.0)],
+            1.0,
+            false,
+        );
+        seg.remove_zero_length_moves(1e-6);
+        let xy: Vec<(Real, Real)> = seg.points.iter().map(|p| (p.x, p.y)).collect();
+        assert_eq!(xy, vec![(0.0, 0.0), (5.0, 0.0), (5.0, 0.01), (5.0, 5.0)]);
+        assert!(seg.edges().all(|(a, b)| (b - a).norm() > 1e-6));
+    }
+
+    #[test]
+    fn clone_layer_copies_a_layer_to_a_new_height() {
+        let layer = |x: Real, z: Real| path(&[(x, 0.0), (x + 5.0, 0.0), (x + 5.0, 5.0)], z, true);
+        let mut set = ToolpathSet { segments: vec![layer(0.0, 0.2), layer(10.0, 0.2), layer(0.0, 0.4), layer(0.0, 0.6)] };
+        set.clone_layer(0.2, 0.4, 1e-6);
+        let summary: Vec<(Real, Real, Real)> = set
+            .segments
+            .iter()
+            .map(|seg| (seg.points[0].x, seg.points[0].z, seg.layer_z.unwrap()))
+            .collect();
+        // The copies join the end of the existing 0.4 layer, before 0.6.
+        assert_eq!(
+            summary,
+            vec![(0.0, 0.2, 0.2), (10.0, 0.2, 0.2), (0.0, 0.4, 0.4), (0.0, 0.4, 0.4), (10.0, 0.4, 0.4), (0.0, 0.6, 0.6)]
+        );
+        assert_eq!(set.segments[4].points[2], Point3::new(15.0, 5.0, 0.4));
+
+        set.clone_layer(0.2, 1.0, 1e-6);
+        assert_eq!(set.segments.len(), 8);
+        assert_eq!(set.layer_ranges().last(), Some(&(6..8)));
+    }
+
+    #[test]
+    fn mrr_profile_is_flat_for_constant_depth_and_peaks_on_deep_levels() {
+        let constant = ToolpathSet { segments: vec![square(9.0), square(8.0), square(7.0)] };
+        assert_eq!(mrr_profile(&constant, 6.0, 1.0, 600.0), MrrReport { average: 3600.0, peak: 3600.0 });
+
+        // Depths 1, 1 and 3 over equal lengths: the last level removes three times as fast.
+        let varying = ToolpathSet { segments: vec![square(9.0), square(8.0), square(5.0)] };
+        let report = mrr_profile(&varying, 6.0, 1.0, 600.0);
+        assert!((report.average - 6000.0).abs() < 1e-9);
+        assert_eq!(report.peak, 10800.0);
+    }
+
+    #[test]
+    fn stock_to_leave_adds_to_the_tool_radius() {
+        let cube = CSG::cube(10.0, 10.0, 5.0, None);
+        let extent = |stock_to_leave: Real| {
+            let cfg = SubtractiveConfig { max_z: 2.0, min_z: 2.0, tool_diameter: 4.0, stock_to_leave, ..Default::default() };
+            let set = SubtractiveToolpathGenerator.generate_toolpaths(&cube, &cfg);
+            assert_eq!(set.segments.len(), 1);
+            let xs = set.segments[0].points.iter().map(|p| p.x);
+            (xs.clone().fold(Real::MAX, Real::min), xs.fold(Real::MIN, Real::max))
+        };
+        assert_eq!(extent(0.0), (-2.0, 12.0));
+        assert_eq!(extent(0.5), (-2.5, 12.5));
+    }
+
+    #[test]
+    fn to_layer_polygons_pairs_annulus_boundary_with_its_hole() {
+        // Both loops wound CW, plus an open path that must be left out.
+        let outer = path(&[(0.0, 0.0), (0.0, 10.0), (10.0, 10.0), (10.0, 0.0)], 1.0, true);
+        let hole = path(&[(3.0, 3.0), (3.0, 7.0), (7.0, 7.0), (7.0, 3.0)], 1.0, true);
+        let stray = path(&[(20.0, 0.0), (30.0, 0.0)], 1.0, false);
+        let set = ToolpathSet { segments: vec![hole, stray, outer] };
+        let layers = set.to_layer_polygons(1e-6);
+        assert_eq!(layers.len(), 1);
+        assert_eq!(layers[0].z, Some(1.0));
+        assert_eq!(layers[0].polygons.len(), 1);
+        let annulus = &layers[0].polygons[0];
+        assert_eq!(annulus.boundary.area_xy_closed(), 100.0);
+        assert_eq!(annulus.holes.len(), 1);
+        assert_eq!(annulus.holes[0].area_xy_closed(), -16.0);
+    }
+
+    #[test]
+    fn simplify_stays_within_its_tolerance() {
+        let circle: Vec<(Real, Real)> =
+            (0..360).map(|i| (i as Real).to_radians()).map(|a| (10.0 * a.cos(), 10.0 * a.sin())).collect();
+        let wiggle: Vec<(Real, Real)> = (0..=100).map(|i| (i as Real * 0.1, 0.02 * (i % 3) as Real)).collect();
+        for (original, tolerance) in [(path(&circle, 1.0, true), 0.05), (path(&wiggle, 1.0, false), 0.05)] {
+            let mut simplified = original.clone();
+            simplified.simplify(tolerance);
+            assert!(simplified.points.len() * 4 < original.points.len());
+            let deviation = max_deviation(&original, &simplified);
+            assert!(deviation > 0.0 && deviation <= tolerance, "deviation {deviation}");
         }
     }
+
+    #[test]
+    fn subtractive_cuts_open_profiles_uncompensated() {
+        // A lone vertical wall slices to an open profile along y = 0.
+        let corner = |x, z| Vertex::new(Point3::new(x, 0.0, z), Vector3::y());
+        let wall: Polygon<()> =
+            Polygon::new(vec![corner(0.0, 0.0), corner(10.0, 0.0), corner(10.0, 4.0), corner(0.0, 4.0)], false, None);
+        let cfg =
+            SubtractiveConfig { max_z: 2.0, min_z: 2.0, tool_diameter: 2.0, lead_length: 3.0, ..Default::default() };
+        let set = SubtractiveToolpathGenerator.generate_toolpaths(&CSG::from_polygons(&[wall]), &cfg);
+        assert_eq!(set.segments.len(), 1);
+        let profile = &set.segments[0];
+        assert!(!profile.closed);
+        assert!(profile.points.iter().all(|p| p.y == 0.0));
+        let xs = profile.points.iter().map(|p| p.x);
+        assert_eq!((xs.clone().fold(Real::MAX, Real::min), xs.fold(Real::MIN, Real::max)), (-3.0, 13.0));
+    }
+
+    #[test]
+    fn subtractive_open_profile_around_a_hole_leaves_the_hole_a_hole() {
+        // 20x20x2 plate with a 4x4 hole, and an open U of wall around the hole.
+        let hole = CSG::cube(4.0, 4.0, 4.0, None).translate(Vector3::new(8.0, 8.0, -1.0));
+        let plate = CSG::cube(20.0, 20.0, 2.0, None).difference(&hole);
+        let wall = |(x0, y0): (Real, Real), (x1, y1): (Real, Real)| {
+            let corner = |x, y, z| Vertex::new(Point3::new(x, y, z), Vector3::z());
+            let corners = vec![corner(x0, y0, 0.0), corner(x1, y1, 0.0), corner(x1, y1, 2.0), corner(x0, y0, 2.0)];
+            Polygon::new(corners, false, None)
+        };
+        let u = vec![wall((6.0, 14.0), (6.0, 6.0)), wall((6.0, 6.0), (14.0, 6.0)), wall((14.0, 6.0), (14.0, 14.0))];
+        let part = CSG::from_polygons(&[plate.polygons, u].concat());
+        let cfg = SubtractiveConfig { max_z: 1.0, min_z: 1.0, tool_diameter: 1.0, ..Default::default() };
+        let set = SubtractiveToolpathGenerator.generate_toolpaths(&part, &cfg);
+        let areas: Vec<(bool, Real)> = set.segments.iter().map(|seg| (seg.closed, seg.area_xy_closed())).collect();
+        // Outer wall grows, the hole shrinks to 3x3, and the U is cut as sliced.
+        let expected = [(true, 400.0 + 40.0 + PI * 0.25), (true, -9.0), (false, -64.0)];
+        assert_eq!(areas.len(), expected.len());
+        for ((closed, area), (want_closed, want_area)) in areas.iter().zip(expected) {
+            assert_eq!(*closed, want_closed);
+            assert!((area - want_area).abs() < 0.1, "area {area} != {want_area}");
+        }
+    }
+
+    #[test]
+    fn add_leads_extends_both_ends_of_open_profile() {
+        let mut seg = path(&[(0.0, 0.0), (5.0, 0.0), (5.0, 5.0)], 1.0, false);
+        seg.add_leads(2.0);
+        assert!(!seg.closed);
+        assert_eq!(seg.points.first(), Some(&Point3::new(-2.0, 0.0, 1.0)));
+        assert_eq!(seg.points.last(), Some(&Point3::new(5.0, 7.0, 1.0)));
+        assert_eq!(seg.edges().count(), 4);
+    }
+
+    #[test]
+    fn add_leads_skips_coincident_end_points() {
+        let mut seg = path(&[(0.0, 0.0), (0.0, 0.0), (5.0, 0.0)], 0.0, false);
+        seg.add_leads(1.0);
+        assert_eq!(seg.points.first(), Some(&Point3::new(-1.0, 0.0, 0.0)));
+        assert_eq!(seg.points.last(), Some(&Point3::new(6.0, 0.0, 0.0)));
+    }
+
+    #[test]
+    fn add_leads_leaves_closed_loops_alone() {
+        let mut seg = path(&[(0.0, 0.0), (5.0, 0.0), (5.0, 5.0)], 0.0, true);
+        seg.add_leads(1.0);
+        assert_eq!(seg.points.len(), 3);
+    }
 }
@@ -3,7 +3,9 @@ use ironpath::AdditiveConfig;
 use ironpath::SubtractiveToolpathGenerator;
 use ironpath::SubtractiveConfig;
 use ironpath::ToolpathGenerator;
+use ironpath::{to_gcode, GcodeConfig};
 
+#[allow(clippy::upper_case_acronyms)]
 type CSG = csgrs::csg::CSG<()>;
 
 fn main() {
@@ -19,11 +21,13 @@ fn main() {
         layer_height: 1.0,
         min_z: 0.0,
         max_z: 10.0,
+        ..Default::default()
     };
     let subtractive_cfg = SubtractiveConfig {
         step_down: 2.0,
         min_z: 0.0,
         max_z: 10.0,
+        ..Default::default()
     };
 
     // 4) Generate toolpaths
@@ -33,8 +37,13 @@ fn main() {
     let subtractive_paths = subtractive_slicer.generate_toolpaths(&csg_cube, &subtractive_cfg);
     println!("Subtractive paths: {:?}", subtractive_paths);
 
+    // 5) Convert the additive `ToolpathSet` into G-code.
+    match to_gcode(&additive_paths, &GcodeConfig::default()) {
+        Ok(gcode) => println!("{}", gcode),
+        Err(err) => eprintln!("G-code export failed: {}", err),
+    }
+
     // From here, we'll:
-    // - Convert the `ToolpathSet` into actual G-code,
     // - Apply tool compensation, feed rates, etc.
     // - Possibly visualize or analyze the paths.
 }
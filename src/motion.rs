@@ -0,0 +1,383 @@
+//! Motion planning helpers: acceleration-aware time estimation and a simple
+//! machine simulation.
+
+use csgrs::float_types::Real;
+use nalgebra::{Point3, Vector3};
+
+use crate::{contour, gcode, ExtrusionConfig, ToolpathSet};
+
+/// Machine motion limits used to plan velocities along a toolpath.
+#[derive(Debug, Clone, Copy)]
+pub struct MotionLimits {
+    /// Maximum acceleration, in units/s².
+    pub max_accel: Real,
+    /// Largest instantaneous change in velocity allowed at a corner, in units/s.
+    pub jerk: Real,
+}
+
+impl Default for MotionLimits {
+    fn default() -> Self {
+        MotionLimits {
+            max_accel: 1000.0,
+            jerk: 10.0,
+        }
+    }
+}
+
+/// A straight move at a requested speed (units/s).
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct Move {
+    pub from: Point3<Real>,
+    pub to: Point3<Real>,
+    pub speed: Real,
+}
+
+impl Move {
+    fn length(&self) -> Real {
+        (self.to - self.from).norm()
+    }
+
+    fn direction(&self) -> Vector3<Real> {
+        (self.to - self.from) / self.length()
+    }
+}
+
+/// Every move the machine makes for `set`: a travel to the start of each segment
+/// and feed moves along it. Feeds are in units/min; zero-length moves are skipped.
+pub(crate) fn moves(set: &ToolpathSet, feed_rate: Real, travel_rate: Real) -> Vec<Move> {
+    let mut moves = Vec::new();
+    let mut position: Option<Point3<Real>> = None;
+    let push = |moves: &mut Vec<Move>, from: Point3<Real>, to: Point3<Real>, rate: Real| {
+        if (to - from).norm() > Real::EPSILON {
+            moves.push(Move { from, to, speed: rate / 60.0 });
+        }
+    };
+    for seg in &set.segments {
+        let Some(start) = seg.points.first() else {
+            continue;
+        };
+        if let Some(from) = position {
+            push(&mut moves, from, *start, travel_rate);
+        }
+        for (a, b) in seg.edges() {
+            push(&mut moves, a, b, feed_rate);
+        }
+        position = seg.end_point();
+    }
+    moves
+}
+
+/// Highest speed at which the machine can pass from `a` into `b` without the
+/// velocity vector jumping by more than `limits.jerk`.
+pub(crate) fn junction_speed(a: &Move, b: &Move, limits: &MotionLimits) -> Real {
+    let cruise = a.speed.min(b.speed);
+    // |v·d1 - v·d2| = v · |d1 - d2|, so v ≤ jerk / |d1 - d2|.
+    let change = (a.direction() - b.direction()).norm();
+    if change <= Real::EPSILON {
+        cruise
+    } else {
+        (limits.jerk / change).min(cruise)
+    }
+}
+
+/// Plan entry/exit speeds with a lookahead over the whole move list: junction
+/// limits first, then a backward pass so every move can still brake for what
+/// follows, then a forward pass so every move can actually reach its exit speed.
+/// Runs of tiny moves that are too short to brake in get carried through at
+/// near-constant speed instead of stopping at each vertex.
+pub(crate) fn plan_speeds(moves: &[Move], limits: &MotionLimits) -> Vec<(Real, Real)> {
+    let n = moves.len();
+    let mut junction = vec![0.0; n + 1];
+    for i in 1..n {
+        junction[i] = junction_speed(&moves[i - 1], &moves[i], limits);
+    }
+
+    let accel = limits.max_accel;
+    for i in (0..n).rev() {
+        let reachable = (junction[i + 1] * junction[i + 1] + 2.0 * accel * moves[i].length()).sqrt();
+        junction[i] = junction[i].min(reachable);
+    }
+    for i in 0..n {
+        let reachable = (junction[i] * junction[i] + 2.0 * accel * moves[i].length()).sqrt();
+        junction[i + 1] = junction[i + 1].min(reachable);
+    }
+    (0..n).map(|i| (junction[i], junction[i + 1])).collect()
+}
+
+/// Time (s) to cover `length` starting at `v0` and ending at `v1`, cruising at
+/// no more than `v_max` with trapezoidal acceleration `accel`.
+pub(crate) fn trapezoid_time(length: Real, v0: Real, v1: Real, v_max: Real, accel: Real) -> Real {
+    if accel <= 0.0 {
+        return length / v_max;
+    }
+    let accel_dist = (v_max * v_max - v0 * v0) / (2.0 * accel);
+    let decel_dist = (v_max * v_max - v1 * v1) / (2.0 * accel);
+    if accel_dist + decel_dist <= length {
+        (v_max - v0) / accel + (v_max - v1) / accel + (length - accel_dist - decel_dist) / v_max
+    } else {
+        // Never reaches cruise: accelerate to a peak, then brake straight away.
+        let peak = ((2.0 * accel * length + v0 * v0 + v1 * v1) / 2.0).sqrt();
+        (peak - v0) / accel + (peak - v1) / accel
+    }
+}
+
+/// Estimated run time in seconds, accounting for acceleration and cornering with
+/// lookahead across consecutive moves. Feeds are in units/min.
+pub fn estimate_time_accel(set: &ToolpathSet, feed_rate: Real, travel_rate: Real, limits: &MotionLimits) -> Real {
+    let moves = moves(set, feed_rate, travel_rate);
+    plan_speeds(&moves, limits)
+        .into_iter()
+        .zip(&moves)
+        .map(|((v0, v1), m)| trapezoid_time(m.length(), v0, v1, m.speed, limits.max_accel))
+        .sum()
+}
+
+/// Merging of tiny consecutive moves in the G-code output, for controllers that
+/// would otherwise stutter through runs of moves too short to brake within.
+#[derive(Debug, Clone, Copy)]
+pub struct MoveMerging {
+    pub limits: MotionLimits,
+    /// Furthest a dropped point may lie from the merged move.
+    pub tolerance: Real,
+}
+
+/// Collapse runs of moves along `path` that are each shorter than the braking
+/// distance from `feed_rate` (units/min) into single moves, as long as every
+/// dropped point stays within `merging.tolerance` of the merged move.
+pub(crate) fn merge_tiny_moves(path: &[Point3<Real>], feed_rate: Real, merging: &MoveMerging) -> Vec<Point3<Real>> {
+    let speed = feed_rate / 60.0;
+    if path.len() < 3 || merging.limits.max_accel <= 0.0 {
+        return path.to_vec();
+    }
+    let brake = speed * speed / (2.0 * merging.limits.max_accel);
+    // Whether path[from..=to] can be replaced by the single move from -> to.
+    let mergeable = |from: usize, to: usize| {
+        path[from..=to].windows(2).all(|w| (w[1] - w[0]).norm() < brake)
+            && path[from + 1..to].iter().all(|p| contour::distance_to_segment(p, &path[from], &path[to]) <= merging.tolerance)
+    };
+
+    let mut merged = vec![path[0]];
+    let mut anchor = 0;
+    let mut end = 1;
+    while end < path.len() {
+        if end + 1 < path.len() && mergeable(anchor, end + 1) {
+            end += 1;
+        } else {
+            merged.push(path[end]);
+            anchor = end;
+            end += 1;
+        }
+    }
+    merged
+}
+
+/// A ramp entry whose vertical component would plunge faster than the Z axis allows.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct RampWarning {
+    /// Vertical feed the ramp would produce, in units/min.
+    pub vertical_feed: Real,
+    /// Steepest ramp angle (degrees from horizontal) that stays within the plunge limit.
+    pub suggested_max_angle: Real,
+    /// Horizontal run needed to descend one `step_down` at the suggested angle.
+    pub suggested_ramp_length: Real,
+}
+
+/// Check a ramp of `ramp_angle` degrees fed at `feed_rate` along its path: the
+/// vertical feed is `feed_rate * sin(angle)`, and if that exceeds
+/// `max_plunge_feed` a shallower angle (and the run it needs for `step_down`) is
+/// suggested.
+pub fn check_ramp_angle(ramp_angle: Real, step_down: Real, feed_rate: Real, max_plunge_feed: Real) -> Option<RampWarning> {
+    let vertical_feed = feed_rate * ramp_angle.to_radians().sin();
+    if vertical_feed <= max_plunge_feed {
+        return None;
+    }
+    let suggested = (max_plunge_feed / feed_rate).clamp(0.0, 1.0).asin();
+    Some(RampWarning {
+        vertical_feed,
+        suggested_max_angle: suggested.to_degrees(),
+        suggested_ramp_length: if suggested > 0.0 { step_down / suggested.tan() } else { Real::INFINITY },
+    })
+}
+
+/// Machine settings used by `ToolpathSet::simulate`.
+#[derive(Debug, Clone)]
+pub struct SimParams {
+    /// Feed along segments, in units/min.
+    pub feed_rate: Real,
+    /// Feed for travels between segments, in units/min.
+    pub travel_rate: Real,
+    pub limits: MotionLimits,
+    /// Extrusion settings; `None` simulates a machine that doesn't extrude.
+    pub extrusion: Option<ExtrusionConfig>,
+    /// Bead height used for extrusion, as in `GcodeConfig::layer_height`.
+    pub layer_height: Option<Real>,
+}
+
+impl Default for SimParams {
+    fn default() -> Self {
+        SimParams {
+            feed_rate: 1200.0,
+            travel_rate: 6000.0,
+            limits: MotionLimits::default(),
+            extrusion: None,
+            layer_height: None,
+        }
+    }
+}
+
+/// Machine state after running a whole toolpath.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct SimResult {
+    /// Where the tool ends up; `None` for an empty toolpath.
+    pub position: Option<Point3<Real>>,
+    /// Net filament pushed, in the same units as `ExtrusionConfig::filament_diameter`.
+    /// Retractions are undone before the next extrusion, so they cancel out.
+    pub extrusion: Real,
+    /// Run time in seconds, as `estimate_time_accel` computes it.
+    pub time: Real,
+}
+
+/// Step through `set` the way the G-code writer would, tracking position and
+/// extrusion, and time it with the acceleration planner.
+pub(crate) fn simulate(set: &ToolpathSet, params: &SimParams) -> SimResult {
+    let mut position: Option<Point3<Real>> = None;
+    let mut extrusion = 0.0;
+    for range in set.layer_ranges() {
+        let layer = &set.segments[range];
+        let loops = gcode::LayerLoops::new(layer);
+        for seg in layer {
+            let Some(start) = seg.points.first() else {
+                continue;
+            };
+            let moving = position.is_some_and(|p| (p - start).norm() > Real::EPSILON);
+            if let Some(e) = &params.extrusion {
+                // Same rule as the writer: no retraction (and so no prime) over material.
+                let over_gap = !e.retract_only_over_gaps
+                    || position.is_some_and(|from| loops.crosses_gap(&from, start, e.line_width));
+                if moving && over_gap && e.retract_length > 0.0 {
+                    extrusion += e.extra_prime_volume / e.filament_area();
+                }
+                extrusion += seg.length() * e.e_per_unit(params.layer_height);
+            }
+            position = seg.end_point();
+        }
+    }
+    SimResult {
+        position,
+        extrusion,
+        time: estimate_time_accel(set, params.feed_rate, params.travel_rate, &params.limits),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tests::{square, square_at};
+    use crate::{SegmentKind, ToolpathSegment};
+
+    /// A gentle arc of `n` moves, each `step` long.
+    fn fine_arc(n: usize, step: Real) -> Vec<Point3<Real>> {
+        let radius = 50.0;
+        (0..=n)
+            .map(|i| {
+                let angle = i as Real * step / radius;
+                Point3::new(radius * angle.sin(), radius * (1.0 - angle.cos()), 0.0)
+            })
+            .collect()
+    }
+
+    #[test]
+    fn steep_ramp_warns_and_suggests_a_shallower_angle() {
+        // 30 degrees at 1000 mm/min drops at 500 mm/min, over the 300 mm/min limit.
+        let warning = check_ramp_angle(30.0, 2.0, 1000.0, 300.0).unwrap();
+        assert!((warning.vertical_feed - 500.0).abs() < 1e-9);
+        assert!((warning.suggested_max_angle - (0.3 as Real).asin().to_degrees()).abs() < 1e-9);
+        let at_suggested = 1000.0 * warning.suggested_max_angle.to_radians().sin();
+        assert!((at_suggested - 300.0).abs() < 1e-9);
+        assert!((warning.suggested_ramp_length - 2.0 / warning.suggested_max_angle.to_radians().tan()).abs() < 1e-9);
+
+        assert_eq!(check_ramp_angle(10.0, 2.0, 1000.0, 300.0), None);
+    }
+
+    #[test]
+    fn lookahead_carries_speed_through_tiny_moves() {
+        let set = ToolpathSet {
+            segments: vec![ToolpathSegment {
+                points: fine_arc(400, 0.2),
+                kind: SegmentKind::Perimeter,
+                closed: false,
+                tool: 0,
+                layer_z: None,
+            }],
+        };
+        let limits = MotionLimits { max_accel: 1000.0, jerk: 10.0 };
+        let planned = estimate_time_accel(&set, 3000.0, 6000.0, &limits);
+        let stop_start = estimate_time_accel(&set, 3000.0, 6000.0, &MotionLimits { jerk: 0.0, ..limits });
+        let cruise = 80.0 / 50.0;
+        assert!(planned < cruise * 1.1, "planned {planned}s vs cruise {cruise}s");
+        assert!(stop_start > planned * 3.0, "stop-start {stop_start}s vs planned {planned}s");
+    }
+
+    #[test]
+    fn merge_tiny_moves_collapses_short_runs_within_tolerance() {
+        let path = fine_arc(100, 0.2);
+        let merging = MoveMerging { limits: MotionLimits { max_accel: 1000.0, jerk: 10.0 }, tolerance: 0.01 };
+        let merged = merge_tiny_moves(&path, 3000.0, &merging);
+        assert!(merged.len() < path.len() / 4, "{} of {} points kept", merged.len(), path.len());
+        assert_eq!(merged.first(), path.first());
+        assert_eq!(merged.last(), path.last());
+        for p in &path {
+            let deviation = merged
+                .windows(2)
+                .map(|w| contour::distance_to_segment(p, &w[0], &w[1]))
+                .fold(Real::INFINITY, Real::min);
+            assert!(deviation <= 0.01 + 1e-12);
+        }
+    }
+
+    #[test]
+    fn simulate_primes_only_after_gap_crossing_travels() {
+        // An island with an inner wall, then a second island across a gap.
+        let set =
+            ToolpathSet { segments: vec![square(0.2), square_at(2.0, 2.0, 6.0, 0.2), square_at(20.0, 0.0, 10.0, 0.2)] };
+        let extrusion = ExtrusionConfig { extra_prime_volume: 1.0, retract_only_over_gaps: true, ..Default::default() };
+        let prime = extrusion.extra_prime_volume / extrusion.filament_area();
+        let params = SimParams { extrusion: Some(extrusion.clone()), layer_height: Some(0.2), ..Default::default() };
+        let drawn = 104.0 * extrusion.e_per_unit(Some(0.2));
+        let result = set.simulate(&params);
+        assert!((result.extrusion - (drawn + prime)).abs() < 1e-9);
+
+        let always = SimParams {
+            extrusion: Some(ExtrusionConfig { retract_only_over_gaps: false, ..extrusion }),
+            ..params
+        };
+        assert!((set.simulate(&always).extrusion - (drawn + 2.0 * prime)).abs() < 1e-9);
+    }
+
+    #[test]
+    fn simulate_ends_at_the_last_point() {
+        let tail = ToolpathSegment {
+            points: vec![Point3::new(20.0, 0.0, 0.2), Point3::new(30.0, 0.0, 0.2), Point3::new(30.0, 5.0, 0.2)],
+            closed: false,
+            ..square(0.2)
+        };
+        let result = ToolpathSet { segments: vec![square(0.2), tail] }.simulate(&SimParams::default());
+        assert_eq!(result.position, Some(Point3::new(30.0, 5.0, 0.2)));
+        assert_eq!(result.extrusion, 0.0);
+        assert!(result.time > 0.0);
+
+        // A closed loop is walked back to its start.
+        let result = ToolpathSet { segments: vec![square(0.2)] }.simulate(&SimParams::default());
+        assert_eq!(result.position, Some(Point3::new(0.0, 0.0, 0.2)));
+
+        let empty = ToolpathSet { segments: Vec::new() }.simulate(&SimParams::default());
+        assert_eq!(empty, SimResult { position: None, extrusion: 0.0, time: 0.0 });
+    }
+
+    #[test]
+    fn merge_tiny_moves_keeps_long_moves() {
+        let path = vec![Point3::new(0.0, 0.0, 0.0), Point3::new(10.0, 0.0, 0.0), Point3::new(20.0, 0.0, 0.0)];
+        let merging = MoveMerging { limits: MotionLimits::default(), tolerance: 0.1 };
+        assert_eq!(merge_tiny_moves(&path, 1200.0, &merging), path);
+    }
+}